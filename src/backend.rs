@@ -0,0 +1,324 @@
+//! Filesystem specific ways of submitting dedupe requests
+
+use crate::dedup::MAX_DEDUP_DESTINATIONS;
+use crate::fiemap::{self, PhysicalRange};
+use std::collections::HashMap;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io, mem};
+use tracing::debug;
+
+const FIDEDUPERANGE: libc::c_ulong = 0xc018_9436;
+
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+const XFS_SUPER_MAGIC: i64 = 0x5846_5342;
+const BCACHEFS_SUPER_MAGIC: i64 = 0xca45_1a4e;
+const OCFS2_SUPER_MAGIC: i64 = 0x7461_636f;
+
+/// Default of the btrfs `max_inline` mount option
+const BTRFS_DEFAULT_MAX_INLINE: u64 = 2048;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; MAX_DEDUP_DESTINATIONS],
+}
+
+/// Outcome of a single destination of a dedupe request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeStatus {
+    Same,
+    Differs,
+    /// Destination failed with this errno
+    Error(i32),
+}
+
+/// Destination of a dedupe request, updated with the outcome when the request is done
+#[derive(Debug, Clone, Copy)]
+pub struct DedupeDest<'a> {
+    pub file: &'a fs::File,
+    pub offset: u64,
+    pub bytes_deduped: u64,
+    pub status: DedupeStatus,
+}
+
+impl<'a> DedupeDest<'a> {
+    pub fn new(file: &'a fs::File, offset: u64) -> Self {
+        Self {
+            file,
+            offset,
+            bytes_deduped: 0,
+            status: DedupeStatus::Same,
+        }
+    }
+}
+
+/// Everything deduplication needs from the filesystem
+pub trait DedupBackend: Sync {
+    /// Returns false if files under `root` can't be deduplicated
+    fn supports(&self, root: &Path) -> io::Result<bool>;
+
+    /// Opens `path` for reading, or for writing if it's a destination
+    fn open(&self, path: &Path, write: bool) -> io::Result<fs::File> {
+        fs::OpenOptions::new().read(true).write(write).open(path)
+    }
+
+    /// Returns true if both ranges have the same contents. The kernel compares contents itself
+    /// while deduplicating, so this is only needed by backends that don't.
+    fn compare(
+        &self,
+        file1: &fs::File,
+        offset1: u64,
+        file2: &fs::File,
+        offset2: u64,
+        length: u64,
+    ) -> io::Result<bool> {
+        let mut buf1 = vec![0; length as usize];
+        let mut buf2 = vec![0; length as usize];
+        file1.read_exact_at(&mut buf1, offset1)?;
+        file2.read_exact_at(&mut buf2, offset2)?;
+        Ok(buf1 == buf2)
+    }
+
+    /// Deduplicates `length` bytes of `src` at `src_offset` into all `dests` with a single
+    /// request. Fails if there are more than `MAX_DEDUP_DESTINATIONS`.
+    fn dedupe(
+        &self,
+        src: &fs::File,
+        src_offset: u64,
+        length: u64,
+        dests: &mut [DedupeDest],
+    ) -> Result<(), String>;
+
+    /// Like `dedupe`, for any number of `dests`, with one request per `MAX_DEDUP_DESTINATIONS`
+    fn dedupe_all(
+        &self,
+        src: &fs::File,
+        src_offset: u64,
+        length: u64,
+        dests: &mut [DedupeDest],
+    ) -> Result<(), String> {
+        for dests in dests.chunks_mut(MAX_DEDUP_DESTINATIONS) {
+            self.dedupe(src, src_offset, length, dests)?;
+        }
+        Ok(())
+    }
+
+    /// Maps a range of `file` to physical ranges, like `fiemap::physical_ranges`
+    fn physical_ranges(
+        &self,
+        file: &fs::File,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<Option<Vec<PhysicalRange>>>;
+
+    /// Returns true if both ranges are already stored at the same physical location
+    fn is_shared(
+        &self,
+        file1: &fs::File,
+        offset1: u64,
+        file2: &fs::File,
+        offset2: u64,
+        length: u64,
+    ) -> bool {
+        match (
+            self.physical_ranges(file1, offset1, length),
+            self.physical_ranges(file2, offset2, length),
+        ) {
+            (Ok(Some(ranges1)), Ok(Some(ranges2))) => ranges1 == ranges2,
+            _ => false,
+        }
+    }
+}
+
+/// Backend submitting `FIDEDUPERANGE` requests to the kernel
+#[derive(Default)]
+pub struct KernelBackend {
+    /// Whether each device supports deduplication, so the filesystem isn't looked up on every
+    /// request
+    supported: Mutex<HashMap<u64, bool>>,
+}
+
+impl KernelBackend {
+    /// Returns true if the filesystem of `file` supports deduplication, detected once per device
+    fn is_supported(&self, file: &fs::File) -> io::Result<bool> {
+        let dev = file.metadata()?.dev();
+        if let Some(supported) = self.supported.lock().unwrap().get(&dev) {
+            return Ok(*supported);
+        }
+        let supported = supports_dedupe(file)?;
+        self.supported.lock().unwrap().insert(dev, supported);
+        Ok(supported)
+    }
+}
+
+impl DedupBackend for KernelBackend {
+    fn supports(&self, root: &Path) -> io::Result<bool> {
+        let supported = self.is_supported(&fs::File::open(root)?)?;
+        debug!("Deduplication supported on {root:?}: {supported}");
+        Ok(supported)
+    }
+
+    fn dedupe(
+        &self,
+        src: &fs::File,
+        src_offset: u64,
+        length: u64,
+        dests: &mut [DedupeDest],
+    ) -> Result<(), String> {
+        if !self.is_supported(src).map_err(|e| e.to_string())? {
+            return Err("Filesystem does not support deduplication".to_string());
+        }
+        dedupe_range(src, src_offset, length, dests)
+    }
+
+    fn physical_ranges(
+        &self,
+        file: &fs::File,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<Option<Vec<PhysicalRange>>> {
+        fiemap::physical_ranges(file, offset, length)
+    }
+}
+
+/// Returns true if the filesystem of `file` supports `FIDEDUPERANGE`, like btrfs, XFS, bcachefs
+/// and OCFS2
+pub fn supports_dedupe(file: &fs::File) -> io::Result<bool> {
+    Ok(matches!(
+        statfs(file)?.f_type as i64,
+        BTRFS_SUPER_MAGIC | XFS_SUPER_MAGIC | BCACHEFS_SUPER_MAGIC | OCFS2_SUPER_MAGIC
+    ))
+}
+
+fn statfs(file: &fs::File) -> io::Result<libc::statfs> {
+    let mut statfs: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut statfs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(statfs)
+}
+
+/// Decodes the octal escapes of spaces and other special characters in `/proc/self/mountinfo`
+fn unescape_mountinfo(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(position) = rest.find('\\') {
+        unescaped.push_str(&rest[..position]);
+        let escape = rest.get(position + 1..position + 4);
+        match escape.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[position + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[position + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Superblock options of the filesystem mounted closest above `path`, which must be absolute
+fn mount_options(path: &Path) -> io::Result<Option<String>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    // Later mounts hide earlier ones on the same mount point
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let mount_point = PathBuf::from(unescape_mountinfo(fields.get(4)?));
+            let separator = fields.iter().position(|&field| field == "-")?;
+            let options = fields.get(separator + 3)?;
+            path.starts_with(&mount_point)
+                .then(|| (mount_point, options.to_string()))
+        })
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(_, options)| options))
+}
+
+/// Size up to which files under `root` may be stored inline in filesystem metadata. Inline
+/// files can't be deduplicated. Returns `None` if the filesystem doesn't inline file data.
+pub fn max_inline_size(root: &Path) -> io::Result<Option<u64>> {
+    let root = fs::canonicalize(root)?;
+    let statfs = statfs(&fs::File::open(&root)?)?;
+    if statfs.f_type as i64 != BTRFS_SUPER_MAGIC {
+        return Ok(None);
+    }
+
+    let max_inline = mount_options(&root)?
+        .and_then(|options| {
+            options
+                .split(',')
+                .find_map(|option| option.strip_prefix("max_inline="))
+                .and_then(|value| value.parse().ok())
+        })
+        .unwrap_or(BTRFS_DEFAULT_MAX_INLINE);
+    // Only files smaller than a sector are inlined
+    Ok(Some(max_inline.min(statfs.f_bsize as u64 - 1)))
+}
+
+/// Makes a single `FIDEDUPERANGE` request, for at most `MAX_DEDUP_DESTINATIONS` destinations.
+/// Each destination gets its own status, so a failing file doesn't fail the others.
+fn dedupe_range(
+    src: &fs::File,
+    src_offset: u64,
+    length: u64,
+    dests: &mut [DedupeDest],
+) -> Result<(), String> {
+    if dests.len() > MAX_DEDUP_DESTINATIONS {
+        return Err(format!(
+            "Too many destinations: {}, at most {MAX_DEDUP_DESTINATIONS}",
+            dests.len()
+        ));
+    }
+
+    let mut range = FileDedupeRange {
+        src_offset,
+        src_length: length,
+        dest_count: dests.len() as u16,
+        reserved1: 0,
+        reserved2: 0,
+        info: [FileDedupeRangeInfo::default(); MAX_DEDUP_DESTINATIONS],
+    };
+    for (info, dest) in range.info.iter_mut().zip(dests.iter()) {
+        info.dest_fd = dest.file.as_raw_fd() as i64;
+        info.dest_offset = dest.offset;
+    }
+
+    if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) } < 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+
+    for (dest, info) in dests.iter_mut().zip(&range.info) {
+        dest.bytes_deduped = info.bytes_deduped;
+        dest.status = match info.status {
+            FILE_DEDUPE_RANGE_SAME => DedupeStatus::Same,
+            FILE_DEDUPE_RANGE_DIFFERS => DedupeStatus::Differs,
+            errno => DedupeStatus::Error(-errno),
+        };
+    }
+    Ok(())
+}
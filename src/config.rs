@@ -0,0 +1,216 @@
+//! Settings from a TOML config file, merged with the command line
+//!
+//! Every setting is optional. Settings from the command line override those of the config file,
+//! lists given on the command line replace the lists of the config file.
+//!
+//! ```toml
+//! roots = ["/mnt/data"]
+//! block-size = 4096
+//! db = "/var/lib/fsdedup/hashes.db"
+//! hash = "xxh3"
+//!
+//! [filter]
+//! exclude = ["*.sqlite-wal"]
+//! min-size = 65536
+//!
+//! [limits]
+//! scan-threads = 2
+//! memory-limit = 1024
+//! ```
+
+use crate::filter::{FileFilter, FilterError};
+use crate::hash::HashAlgorithm;
+use crate::plan::SourcePolicy;
+use crate::scan::ChunkingMode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+/// Config file read when no other is given
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/fsdedup.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Filter(FilterError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(path, e) => write!(f, "Could not read {path:?}: {e}"),
+            Self::Parse(path, e) => write!(f, "Invalid config file {path:?}: {e}"),
+            Self::Filter(e) => write!(f, "Invalid filter: {e}"),
+        }
+    }
+}
+
+impl From<FilterError> for ConfigError {
+    fn from(e: FilterError) -> Self {
+        Self::Filter(e)
+    }
+}
+
+/// Returns `other`, unless it's empty
+fn replace_list<T>(list: Vec<T>, other: Vec<T>) -> Vec<T> {
+    if other.is_empty() {
+        list
+    } else {
+        other
+    }
+}
+
+/// Rules deciding which files are scanned, see `FileFilter`
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FilterConfig {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Files with more exclude globs, one per line
+    pub exclude_from: Vec<PathBuf>,
+    pub exclude_regex: Vec<String>,
+    pub ignore_file: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl FilterConfig {
+    fn merge(self, other: FilterConfig) -> Self {
+        Self {
+            include: replace_list(self.include, other.include),
+            exclude: replace_list(self.exclude, other.exclude),
+            exclude_from: replace_list(self.exclude_from, other.exclude_from),
+            exclude_regex: replace_list(self.exclude_regex, other.exclude_regex),
+            ignore_file: replace_list(self.ignore_file, other.ignore_file),
+            min_size: other.min_size.or(self.min_size),
+            max_size: other.max_size.or(self.max_size),
+        }
+    }
+
+    pub fn file_filter(&self) -> Result<FileFilter, ConfigError> {
+        let mut exclude = self.exclude.clone();
+        for path in &self.exclude_from {
+            let patterns = FileFilter::read_patterns(path)
+                .map_err(|e| ConfigError::IoError(path.clone(), e))?;
+            exclude.extend(patterns);
+        }
+
+        let filter = FileFilter::new(
+            &self.include,
+            &exclude,
+            &self.exclude_regex,
+            &self.ignore_file,
+        )?;
+        Ok(filter.size_limits(self.min_size.unwrap_or(0), self.max_size))
+    }
+}
+
+/// Threads and memory a run may use
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitsConfig {
+    /// Threads hashing files, all CPUs if not set
+    pub scan_threads: Option<usize>,
+    /// Threads making dedupe requests
+    pub dedup_threads: Option<usize>,
+    /// Memory for the block index in MiB
+    pub memory_limit: Option<usize>,
+}
+
+impl LimitsConfig {
+    fn merge(self, other: LimitsConfig) -> Self {
+        Self {
+            scan_threads: other.scan_threads.or(self.scan_threads),
+            dedup_threads: other.dedup_threads.or(self.dedup_threads),
+            memory_limit: other.memory_limit.or(self.memory_limit),
+        }
+    }
+}
+
+/// Settings of a run. Tables must stay after plain values, to be written as TOML.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub roots: Vec<PathBuf>,
+    pub block_size: Option<usize>,
+    /// Scanned files waiting for deduplication
+    pub dedup_queue: Option<usize>,
+    pub db: Option<PathBuf>,
+    pub incremental: Option<bool>,
+    pub whole_file: Option<bool>,
+    pub hash: Option<HashAlgorithm>,
+    pub chunking: Option<ChunkingMode>,
+    pub cdc_avg_size: Option<usize>,
+    pub two_phase: Option<bool>,
+    pub source_policy: Option<SourcePolicy>,
+    pub filter: FilterConfig,
+    pub limits: LimitsConfig,
+}
+
+impl Config {
+    /// Values used when neither the config file nor the command line sets them
+    pub fn defaults() -> Self {
+        Self {
+            roots: vec![],
+            block_size: Some(4096),
+            dedup_queue: Some(32),
+            db: None,
+            incremental: Some(false),
+            whole_file: Some(false),
+            hash: Some(HashAlgorithm::Crc64),
+            chunking: Some(ChunkingMode::Fixed),
+            cdc_avg_size: Some(65536),
+            two_phase: Some(false),
+            source_policy: None,
+            filter: FilterConfig {
+                min_size: Some(0),
+                ..FilterConfig::default()
+            },
+            limits: LimitsConfig {
+                dedup_threads: Some(1),
+                ..LimitsConfig::default()
+            },
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::IoError(path.to_owned(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Loads `DEFAULT_CONFIG_PATH`, or returns an empty config if it doesn't exist
+    pub fn load_default() -> Result<Self, ConfigError> {
+        match Self::load(Path::new(DEFAULT_CONFIG_PATH)) {
+            Err(ConfigError::IoError(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            res => res,
+        }
+    }
+
+    /// Returns settings of `self` overridden by the ones set in `other`
+    pub fn merge(self, other: Config) -> Self {
+        Self {
+            roots: replace_list(self.roots, other.roots),
+            block_size: other.block_size.or(self.block_size),
+            dedup_queue: other.dedup_queue.or(self.dedup_queue),
+            db: other.db.or(self.db),
+            incremental: other.incremental.or(self.incremental),
+            whole_file: other.whole_file.or(self.whole_file),
+            hash: other.hash.or(self.hash),
+            chunking: other.chunking.or(self.chunking),
+            cdc_avg_size: other.cdc_avg_size.or(self.cdc_avg_size),
+            two_phase: other.two_phase.or(self.two_phase),
+            source_policy: other.source_policy.or(self.source_policy),
+            filter: self.filter.merge(other.filter),
+            limits: self.limits.merge(other.limits),
+        }
+    }
+
+    /// Fails if a path isn't valid UTF-8, which TOML can't represent
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
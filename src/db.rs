@@ -0,0 +1,260 @@
+use crate::hash::{BlockHasher, HashAlgorithm};
+use crate::scan::{ScanOptions, ScanResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::io::{BufReader, BufWriter, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io, mem};
+use tracing::{info, warn};
+
+const DB_VERSION: u32 = 9;
+
+/// Header of the database file, readable regardless of the hash type
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbHeader {
+    pub version: u32,
+    pub hash: HashAlgorithm,
+    pub options: ScanOptions,
+}
+
+/// Contents of the database file following `DbHeader`
+#[derive(Serialize, Deserialize)]
+struct DbBody<H> {
+    #[serde(with = "crate::serde_path::map")]
+    generations: HashMap<PathBuf, u64>,
+    files: Vec<ScanResult<H>>,
+}
+
+/// Scan results from previous runs, keyed by absolute file path
+pub struct HashDb<H: BlockHasher> {
+    options: ScanOptions,
+    /// Btrfs subvolume generation of each root directory at the start of the run
+    generations: HashMap<PathBuf, u64>,
+    files: HashMap<PathBuf, ScanResult<H::Hash>>,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum DbError {
+    IoError(io::Error),
+    Encoding(bincode::Error),
+    /// Written by another version or with another hash algorithm
+    Incompatible(DbHeader),
+}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+impl From<bincode::Error> for DbError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Encoding(e)
+    }
+}
+
+fn open_file(path: &Path) -> Result<(DbHeader, BufReader<fs::File>), DbError> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let header: DbHeader = bincode::deserialize_from(&mut reader)?;
+    Ok((header, reader))
+}
+
+/// Whether the file of `result` was not replaced or modified since it was scanned
+fn is_unchanged<H>(result: &ScanResult<H>, metadata: &Metadata) -> bool {
+    (result.dev, result.ino) == (metadata.dev(), metadata.ino())
+        && result.size == metadata.len()
+        && metadata.modified().is_ok_and(|mtime| mtime == result.mtime)
+}
+
+/// Reads only the header of the database at `path`, to find the hash type to open it with
+pub fn read_header(path: &Path) -> Result<DbHeader, DbError> {
+    Ok(open_file(path)?.0)
+}
+
+impl<H: BlockHasher> HashDb<H> {
+    pub fn new(options: ScanOptions) -> Self {
+        Self {
+            options,
+            generations: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Loads database from `path`. Returns an empty database if the file does not exist yet, or if
+    /// it was written with different scan parameters.
+    pub fn load(path: &Path, options: ScanOptions) -> Result<Self, DbError> {
+        let (header, reader) = match open_file(path) {
+            Err(DbError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::new(options))
+            }
+            res => res?,
+        };
+        if !Self::is_compatible(&header) || header.options != options {
+            warn!(
+                "Ignoring hash database {:?}: written with version {}, hash {:?}, {:?}",
+                path, header.version, header.hash, header.options
+            );
+            return Ok(Self::new(options));
+        }
+
+        Self::read_body(options, reader)
+    }
+
+    /// Loads database from `path` with the scan parameters it was written with
+    pub fn open(path: &Path) -> Result<Self, DbError> {
+        let (header, reader) = open_file(path)?;
+        if !Self::is_compatible(&header) {
+            return Err(DbError::Incompatible(header));
+        }
+
+        Self::read_body(header.options, reader)
+    }
+
+    fn is_compatible(header: &DbHeader) -> bool {
+        header.version == DB_VERSION && header.hash == H::ALGORITHM
+    }
+
+    fn read_body(options: ScanOptions, mut reader: impl Read) -> Result<Self, DbError> {
+        let body: DbBody<H::Hash> = bincode::deserialize_from(&mut reader)?;
+
+        info!("Loaded {} files from hash database", body.files.len());
+        Ok(Self {
+            options,
+            generations: body.generations,
+            files: body
+                .files
+                .into_iter()
+                .map(|result| (result.path.clone(), result))
+                .collect(),
+        })
+    }
+
+    /// Atomically replaces database at `path` with the current contents
+    pub fn save(self, path: &Path) -> Result<(), DbError> {
+        let header = DbHeader {
+            version: DB_VERSION,
+            hash: H::ALGORITHM,
+            options: self.options,
+        };
+        let body = DbBody {
+            generations: self.generations,
+            files: self.files.into_values().collect(),
+        };
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+        bincode::serialize_into(&mut writer, &header)?;
+        bincode::serialize_into(&mut writer, &body)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(tmp_path, path)?;
+
+        info!("Saved {} files to hash database", body.files.len());
+        Ok(())
+    }
+
+    /// Returns a previous scan result for `path`, if the file was not changed since
+    pub fn lookup(&self, path: &Path, metadata: &Metadata) -> Option<&ScanResult<H::Hash>> {
+        self.files
+            .get(path)
+            .filter(|result| is_unchanged(result, metadata))
+    }
+
+    pub fn insert(&mut self, result: ScanResult<H::Hash>) {
+        self.files.insert(result.path.clone(), result);
+    }
+
+    /// Marks all files as deduplicated, except the destinations of failed dedup attempts
+    pub fn mark_deduplicated(&mut self, failed: &HashSet<PathBuf>) {
+        for (path, result) in &mut self.files {
+            result.deduplicated = !failed.contains(path);
+        }
+    }
+
+    /// Iterates over all files under `root`
+    pub fn files_under<'a>(
+        &'a self,
+        root: &'a Path,
+    ) -> impl Iterator<Item = &'a ScanResult<H::Hash>> {
+        self.files
+            .values()
+            .filter(move |result| result.path.starts_with(root))
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &ScanResult<H::Hash>> {
+        self.files.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn options(&self) -> ScanOptions {
+        self.options
+    }
+
+    /// Btrfs subvolume generation of each root at the start of the last run
+    pub fn generations(&self) -> &HashMap<PathBuf, u64> {
+        &self.generations
+    }
+
+    /// Removes files deleted or modified since they were scanned. Returns the number removed.
+    pub fn gc(&mut self) -> usize {
+        let before = self.files.len();
+        self.files.retain(|path, result| {
+            fs::symlink_metadata(path).is_ok_and(|metadata| is_unchanged(result, &metadata))
+        });
+        self.generations.retain(|root, _| root.exists());
+        before - self.files.len()
+    }
+
+    /// Removes files and generations outside of absolute paths `roots`. Returns the number of
+    /// files removed.
+    pub fn retain_roots(&mut self, roots: &[PathBuf]) -> usize {
+        self.take_outside(roots).len()
+    }
+
+    /// Removes files and generations outside of absolute paths `roots` and returns them
+    pub fn take_outside(&mut self, roots: &[PathBuf]) -> Self {
+        let is_kept = |path: &Path| roots.iter().any(|root| path.starts_with(root));
+        let (files, outside) = mem::take(&mut self.files)
+            .into_iter()
+            .partition(|(path, _)| is_kept(path));
+        self.files = files;
+        let (generations, outside_generations) = mem::take(&mut self.generations)
+            .into_iter()
+            .partition(|(root, _)| is_kept(root));
+        self.generations = generations;
+        Self {
+            options: self.options,
+            generations: outside_generations,
+            files: outside,
+        }
+    }
+
+    /// Adds all files and generations of `other`, replacing those with the same path
+    pub fn extend(&mut self, other: Self) {
+        self.files.extend(other.files);
+        self.generations.extend(other.generations);
+    }
+
+    pub fn generation(&self, root: &Path) -> Option<u64> {
+        self.generations.get(root).copied()
+    }
+
+    pub fn set_generation(&mut self, root: PathBuf, generation: u64) {
+        self.generations.insert(root, generation);
+    }
+}
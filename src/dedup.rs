@@ -1,15 +1,152 @@
-use btrfs::{deduplicate_range, DedupeRange, DedupeRangeDestInfo, DedupeRangeStatus};
+use crate::backend::{DedupBackend, DedupeDest, DedupeStatus};
+use crate::fiemap::PhysicalRange;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
-use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{cmp, fs, io};
 use tracing::{debug, info};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+const FS_IOC_GETVERSION: libc::c_ulong = 0x8008_7601;
+
+/// Maximum length of a single dedupe request. Btrfs silently truncates longer ranges.
+pub const MAX_DEDUP_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Maximum number of destinations in a single dedupe request, so it fits in a page
+pub const MAX_DEDUP_DESTINATIONS: usize = 127;
+
+/// Maximum number of destinations waiting in `DedupQueue` for more destinations with the same
+/// source
+const MAX_QUEUED_DESTINATIONS: usize = 65536;
+
+/// State of a file when it was scanned, checked again right before deduplicating it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileVersion {
+    pub dev: u64,
+    pub ino: u64,
+    pub mtime: SystemTime,
+    pub size: u64,
+    /// Inode generation, see `inode_generation`
+    pub generation: u64,
+}
+
+impl FileVersion {
+    pub fn of(file: &fs::File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: metadata.modified()?,
+            size: metadata.len(),
+            generation: inode_generation(file)?,
+        })
+    }
+}
+
+/// Returns the generation of the inode of `file`, which changes when an inode number is reused.
+/// Returns 0 if the filesystem doesn't keep inode generations.
+pub fn inode_generation(file: &fs::File) -> io::Result<u64> {
+    let mut generation: libc::c_long = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETVERSION as _, &mut generation) } < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Ok(0),
+            _ => Err(e),
+        };
+    }
+    // The kernel only writes a 32-bit value
+    Ok(generation as u32 as u64)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BlockLocation {
+    #[serde(with = "crate::serde_path")]
     pub path: PathBuf,
     pub offset: u64,
     pub length: usize,
+    /// Version of the file seen by the scan
+    pub version: FileVersion,
+}
+
+impl BlockLocation {
+    /// Returns true if `next` starts right where this block ends, in the same file
+    fn is_followed_by(&self, next: &BlockLocation) -> bool {
+        self.path == next.path && self.offset + self.length as u64 == next.offset
+    }
+}
+
+/// Merges runs of consecutive duplicate blocks into ranges, so they can be deduplicated with
+/// a single request and end up in a single shared extent.
+#[derive(Default)]
+pub struct RangeMerger {
+    current: Option<(BlockLocation, BlockLocation)>,
+}
+
+impl RangeMerger {
+    /// Adds a pair of duplicate blocks. Returns the previous range, if the pair doesn't continue it.
+    pub fn push(
+        &mut self,
+        src: BlockLocation,
+        dest: BlockLocation,
+    ) -> Option<(BlockLocation, BlockLocation)> {
+        if let Some((current_src, current_dest)) = &mut self.current {
+            if current_src.is_followed_by(&src)
+                && current_dest.is_followed_by(&dest)
+                && src.length == dest.length
+                && current_src.length + src.length <= MAX_DEDUP_LENGTH
+            {
+                current_src.length += src.length;
+                current_dest.length += dest.length;
+                return None;
+            }
+        }
+
+        self.current.replace((src, dest))
+    }
+
+    /// Returns the last unfinished range
+    pub fn finish(self) -> Option<(BlockLocation, BlockLocation)> {
+        self.current
+    }
+}
+
+/// Groups destinations by source range, so all copies of a range are deduplicated with a single
+/// request, and the source is locked only once.
+#[derive(Default)]
+pub struct DedupQueue {
+    queued: HashMap<BlockLocation, Vec<BlockLocation>>,
+    queued_count: usize,
+}
+
+impl DedupQueue {
+    /// Queues `dest` for deduplication against `src`. Returns batches ready to be submitted.
+    pub fn push(
+        &mut self,
+        src: BlockLocation,
+        dest: BlockLocation,
+    ) -> Vec<(BlockLocation, Vec<BlockLocation>)> {
+        let dests = self.queued.entry(src.clone()).or_default();
+        dests.push(dest);
+        self.queued_count += 1;
+
+        if dests.len() >= MAX_DEDUP_DESTINATIONS {
+            let dests = self.queued.remove(&src).unwrap();
+            self.queued_count -= dests.len();
+            vec![(src, dests)]
+        } else if self.queued_count >= MAX_QUEUED_DESTINATIONS {
+            self.drain()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Returns all queued batches
+    pub fn drain(&mut self) -> Vec<(BlockLocation, Vec<BlockLocation>)> {
+        self.queued_count = 0;
+        self.queued.drain().collect()
+    }
 }
 
 #[derive(Debug)]
@@ -25,65 +162,316 @@ pub enum BlockDedupError {
         offset: u64,
         length: usize,
     },
+    /// Both ranges already point to the same physical extent
+    AlreadyShared {
+        path1: PathBuf,
+        path2: PathBuf,
+    },
+    /// Destination contents differ from the source, or only part of the range was deduplicated
+    Differs {
+        path1: PathBuf,
+        path2: PathBuf,
+        requested: u64,
+        bytes_deduped: u64,
+    },
+    /// File was modified since it was scanned
+    Stale {
+        path: PathBuf,
+    },
     DedupInternal(String),
     FileErrors(Option<io::Error>, Option<io::Error>),
 }
 
-#[tracing::instrument]
-pub fn dedup(block1: BlockLocation, block2: BlockLocation) -> Result<(), BlockDedupError> {
+impl BlockDedupError {
+    /// Name of the variant, used in run reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::SameBlock { .. } => "same_block",
+            Self::SameExtent { .. } => "same_extent",
+            Self::AlreadyShared { .. } => "already_shared",
+            Self::Differs { .. } => "differs",
+            Self::Stale { .. } => "stale",
+            Self::DedupInternal(_) => "dedup_internal",
+            Self::FileErrors(..) => "file_errors",
+        }
+    }
+}
+
+/// Result of a dedupe request for each destination, in the order they were passed. Successful
+/// results hold the number of bytes deduplicated.
+pub type DedupResults = Vec<Result<u64, BlockDedupError>>;
+
+/// Decodes the outcome of a single destination of a dedupe request for `requested` bytes
+fn dest_result(
+    src: &Path,
+    dest: &Path,
+    requested: u64,
+    dest_info: &DedupeDest,
+) -> Result<u64, BlockDedupError> {
+    match dest_info.status {
+        DedupeStatus::Same if dest_info.bytes_deduped == requested => Ok(requested),
+        DedupeStatus::Error(errno) => Err(BlockDedupError::FileErrors(
+            None,
+            Some(io::Error::from_raw_os_error(errno)),
+        )),
+        _ => Err(BlockDedupError::Differs {
+            path1: src.to_path_buf(),
+            path2: dest.to_path_buf(),
+            requested,
+            bytes_deduped: dest_info.bytes_deduped,
+        }),
+    }
+}
+
+/// Opens `path` and returns it with its inode number. Returns `None` if the file is not at
+/// `version` anymore.
+fn open_version<B: DedupBackend>(
+    backend: &B,
+    path: &Path,
+    version: &FileVersion,
+    write: bool,
+) -> io::Result<Option<(fs::File, u64)>> {
+    let file = backend.open(path, write)?;
+    if FileVersion::of(&file)? != *version {
+        return Ok(None);
+    }
+    let ino = file.metadata()?.ino();
+    Ok(Some((file, ino)))
+}
+
+/// Returns true if both ranges are already shared, so they would be skipped by `dedup`. Files
+/// which can't be opened are not shared.
+pub(crate) fn is_shared<B: DedupBackend>(
+    backend: &B,
+    src: &Path,
+    src_offset: u64,
+    dest: &Path,
+    dest_offset: u64,
+    length: u64,
+) -> bool {
+    match (backend.open(src, false), backend.open(dest, false)) {
+        (Ok(src_file), Ok(dest_file)) => {
+            backend.is_shared(&src_file, src_offset, &dest_file, dest_offset, length)
+        }
+        _ => false,
+    }
+}
+
+/// Physical ranges of a source range, looked up once and compared with each destination
+struct SharedSource<'a, B> {
+    backend: &'a B,
+    /// `None` if the source has no comparable physical ranges, so nothing is shared with it
+    ranges: Option<Vec<PhysicalRange>>,
+    length: u64,
+}
+
+impl<'a, B: DedupBackend> SharedSource<'a, B> {
+    fn new(backend: &'a B, src_file: &fs::File, src_offset: u64, length: u64) -> Self {
+        Self {
+            backend,
+            ranges: backend
+                .physical_ranges(src_file, src_offset, length)
+                .ok()
+                .flatten(),
+            length,
+        }
+    }
+
+    /// Like `DedupBackend::is_shared`, without looking up the source again
+    fn is_shared(&self, dest_file: &fs::File, dest_offset: u64) -> bool {
+        self.ranges.as_ref().is_some_and(|ranges| {
+            self.backend
+                .physical_ranges(dest_file, dest_offset, self.length)
+                .is_ok_and(|dest_ranges| dest_ranges.as_ref() == Some(ranges))
+        })
+    }
+}
+
+/// Deduplicates all `dests` against `src` with a single request. Files modified since they were
+/// scanned are skipped. The outer error is returned, if
+/// the request could not be made at all.
+#[tracing::instrument(skip(backend))]
+pub fn dedup<B: DedupBackend>(
+    backend: &B,
+    src: BlockLocation,
+    dests: Vec<BlockLocation>,
+) -> Result<DedupResults, BlockDedupError> {
     debug!(
-        "Trying to DEDUP {:?}[{}..{}], {:?}[{}..{}]",
-        block1.path, block1.offset, block1.length, block2.path, block2.offset, block2.length
+        "Trying to DEDUP {:?}[{}..{}] into {} destinations",
+        src.path,
+        src.offset,
+        src.length,
+        dests.len()
     );
-    if block1 == block2 {
-        return Err(BlockDedupError::SameBlock { block: block1 });
-    }
-
-    match (
-        fs::File::open(block1.path.clone()),
-        fs::OpenOptions::new().write(true).open(block2.path.clone()),
-    ) {
-        (Ok(file1), Ok(file2)) => {
-            let (ino1, ino2) = (
-                file1.metadata().unwrap().ino(),
-                file2.metadata().unwrap().ino(),
-            );
-
-            if (ino1, block1.offset, block1.length) == (ino2, block2.offset, block2.length) {
-                return Err(BlockDedupError::SameExtent {
-                    path1: block1.path,
-                    path2: block2.path,
-                    ino: ino1,
-                    offset: block1.offset,
-                    length: block1.length,
-                });
+
+    let (src_file, src_ino) = match open_version(backend, &src.path, &src.version, false) {
+        Ok(Some(src_file)) => src_file,
+        Ok(None) => return Err(BlockDedupError::Stale { path: src.path }),
+        Err(e) => return Err(BlockDedupError::FileErrors(Some(e), None)),
+    };
+
+    let shared_source = SharedSource::new(backend, &src_file, src.offset, src.length as u64);
+    let mut results = Vec::with_capacity(dests.len());
+    // Index into `results`, path and file of submitted destinations
+    let mut submitted = vec![];
+
+    for dest in dests {
+        if dest == src {
+            results.push(Err(BlockDedupError::SameBlock { block: dest }));
+            continue;
+        }
+
+        let (dest_file, dest_ino) = match open_version(backend, &dest.path, &dest.version, true) {
+            Ok(Some(dest_file)) => dest_file,
+            Ok(None) => {
+                results.push(Err(BlockDedupError::Stale { path: dest.path }));
+                continue;
+            }
+            Err(e) => {
+                results.push(Err(BlockDedupError::FileErrors(None, Some(e))));
+                continue;
             }
+        };
 
-            let mut range = DedupeRange {
-                src_offset: block1.offset,
-                src_length: block1.length as u64,
-                dest_infos: vec![DedupeRangeDestInfo {
-                    dest_fd: file2.as_raw_fd() as i64,
-                    dest_offset: block2.offset,
-                    bytes_deduped: block2.length as u64,
-                    status: DedupeRangeStatus::Same,
-                }],
-            };
+        if (src_ino, src.offset, src.length) == (dest_ino, dest.offset, dest.length) {
+            results.push(Err(BlockDedupError::SameExtent {
+                path1: src.path.clone(),
+                path2: dest.path,
+                ino: src_ino,
+                offset: src.offset,
+                length: src.length,
+            }));
+            continue;
+        }
+
+        if shared_source.is_shared(&dest_file, dest.offset) {
+            results.push(Err(BlockDedupError::AlreadyShared {
+                path1: src.path.clone(),
+                path2: dest.path,
+            }));
+            continue;
+        }
+
+        submitted.push((results.len(), dest.path, dest_file, dest.offset));
+        results.push(Ok(0));
+    }
+
+    if submitted.is_empty() {
+        return Ok(results);
+    }
+
+    let mut dest_infos: Vec<_> = submitted
+        .iter()
+        .map(|(_, _, dest_file, dest_offset)| DedupeDest::new(dest_file, *dest_offset))
+        .collect();
+
+    let length = src.length as u64;
+    backend
+        .dedupe_all(&src_file, src.offset, length, &mut dest_infos)
+        .map_err(BlockDedupError::DedupInternal)?;
 
-            let x = deduplicate_range(file1.as_raw_fd(), &mut range);
+    for ((index, dest_path, ..), dest_info) in submitted.iter().zip(&dest_infos) {
+        results[*index] = dest_result(&src.path, dest_path, length, dest_info);
+    }
+    info!(
+        "DEDUP [{}..{}] into {} of {} destinations",
+        src.offset,
+        src.length,
+        results.iter().filter(|res| res.is_ok()).count(),
+        dest_infos.len()
+    );
 
-            match x {
-                Ok(_) => {
-                    info!(
-                        "DEDUP [{}..{}], [{}..{}]",
-                        block1.offset, block1.length, block2.offset, block2.length
-                    )
+    Ok(results)
+}
+
+/// Deduplicates whole `dests` files against `src`. All files must be as long as `src_version`
+/// says, files modified since they were scanned are skipped. The outer error is returned, if the
+/// source could not be opened or a request could not be made at all.
+#[tracing::instrument(skip(backend))]
+pub fn dedup_files<B: DedupBackend>(
+    backend: &B,
+    src: &Path,
+    src_version: &FileVersion,
+    dests: &[(&Path, FileVersion)],
+) -> Result<DedupResults, BlockDedupError> {
+    let size = src_version.size;
+    let (src_file, _) = match open_version(backend, src, src_version, false) {
+        Ok(Some(src_file)) => src_file,
+        Ok(None) => return Err(BlockDedupError::Stale { path: src.into() }),
+        Err(e) => return Err(BlockDedupError::FileErrors(Some(e), None)),
+    };
+    let shared_source = SharedSource::new(backend, &src_file, 0, size);
+    let mut results = Vec::with_capacity(dests.len());
+
+    // Open destinations in batches, to stay within open file limits
+    for dests in dests.chunks(MAX_DEDUP_DESTINATIONS) {
+        // Index into `results`, path and file of destinations still being deduplicated
+        let mut pending = Vec::with_capacity(dests.len());
+        for (dest, dest_version) in dests {
+            let dest_file = match open_version(backend, dest, dest_version, true) {
+                Ok(Some((dest_file, _))) => dest_file,
+                Ok(None) => {
+                    results.push(Err(BlockDedupError::Stale {
+                        path: dest.to_path_buf(),
+                    }));
+                    continue;
+                }
+                Err(e) => {
+                    results.push(Err(BlockDedupError::FileErrors(None, Some(e))));
+                    continue;
                 }
-                Err(e) => return Err(BlockDedupError::DedupInternal(e)),
+            };
+
+            if shared_source.is_shared(&dest_file, 0) {
+                results.push(Err(BlockDedupError::AlreadyShared {
+                    path1: src.to_path_buf(),
+                    path2: dest.to_path_buf(),
+                }));
+            } else {
+                pending.push((results.len(), *dest, dest_file));
+                results.push(Ok(0));
             }
         }
-        (e1, e2) => return Err(BlockDedupError::FileErrors(e1.err(), e2.err())),
+
+        let mut offset = 0;
+        while offset < size && !pending.is_empty() {
+            let length = cmp::min(size - offset, MAX_DEDUP_LENGTH as u64);
+
+            let mut dest_infos: Vec<_> = pending
+                .iter()
+                .map(|(_, _, dest_file)| DedupeDest::new(dest_file, offset))
+                .collect();
+            backend
+                .dedupe_all(&src_file, offset, length, &mut dest_infos)
+                .map_err(BlockDedupError::DedupInternal)?;
+
+            // Destinations that differ or fail are left alone for the rest of the file
+            let mut keep = Vec::with_capacity(pending.len());
+            for ((index, dest, _), dest_info) in pending.iter().zip(&dest_infos) {
+                let deduped_before = *results[*index].as_ref().unwrap();
+                results[*index] = match dest_result(src, dest, length, dest_info) {
+                    Ok(bytes) => Ok(deduped_before + bytes),
+                    Err(BlockDedupError::Differs { .. }) => Err(BlockDedupError::Differs {
+                        path1: src.to_path_buf(),
+                        path2: dest.to_path_buf(),
+                        requested: size,
+                        bytes_deduped: deduped_before + dest_info.bytes_deduped,
+                    }),
+                    Err(e) => Err(e),
+                };
+                keep.push(results[*index].is_ok());
+            }
+            let mut keep = keep.into_iter();
+            pending.retain(|_| keep.next().unwrap());
+            offset += length;
+        }
     }
 
-    Ok(())
+    info!(
+        "DEDUP whole file {:?} into {} of {} files",
+        src,
+        results.iter().filter(|res| res.is_ok()).count(),
+        dests.len()
+    );
+    Ok(results)
 }
@@ -0,0 +1,629 @@
+//! High-level API running a whole scan and deduplication
+
+use crate::backend::{DedupBackend, KernelBackend};
+use crate::config::{Config, ConfigError};
+use crate::db::HashDb;
+use crate::dedup::{self, BlockLocation, DedupQueue, FileVersion, RangeMerger, MAX_DEDUP_LENGTH};
+use crate::dry_run::DryRunRecorder;
+use crate::filter::FileFilter;
+use crate::hash::{self, BlockHasher, HashAlgorithm};
+use crate::incremental;
+use crate::index::BlockIndex;
+use crate::plan::{self, PlanFormat, PlanReader, PlanWriter, SourcePolicy};
+use crate::report::RunReport;
+use crate::scan::{self, Chunking, ChunkingMode, ScanOptions, ScanResult};
+use crate::workers::DedupWorkers;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+use std::{cmp, fs, io};
+use tracing::warn;
+
+/// Files grouped by size and contents hash
+type FileGroups<H> = HashMap<(u64, H), Vec<ScanResult<H>>>;
+
+/// What a run does with the scanned files
+enum RunMode {
+    Dedup,
+    /// Write dedupe requests to a plan file instead of making them
+    Plan(PlanWriter),
+    /// Only hash files and update the hash database
+    Scan,
+}
+
+/// Block matching and deduplication state of a run
+struct Deduper<'scope, H: BlockHasher, B: DedupBackend> {
+    backend: &'scope B,
+    workers: DedupWorkers<'scope>,
+    block_locations: BlockIndex<H>,
+    queue: DedupQueue,
+    /// Present in dry run mode
+    recorder: Option<DryRunRecorder>,
+    /// Present when writing a plan file
+    plan: Option<PlanWriter>,
+    report: RunReport,
+}
+
+impl<'scope, H: BlockHasher, B: DedupBackend> Deduper<'scope, H, B> {
+    fn new(
+        backend: &'scope B,
+        workers: DedupWorkers<'scope>,
+        recorder: Option<DryRunRecorder>,
+        plan: Option<PlanWriter>,
+        memory_limit: Option<usize>,
+    ) -> Self {
+        Self {
+            backend,
+            workers,
+            block_locations: BlockIndex::new(memory_limit),
+            queue: DedupQueue::default(),
+            recorder,
+            plan,
+            report: RunReport::default(),
+        }
+    }
+
+    fn submit(&self, batches: Vec<(BlockLocation, Vec<BlockLocation>)>) {
+        for (src, dests) in batches {
+            self.workers.dedup(src, dests);
+        }
+    }
+
+    /// Queues a range for deduplication, or records it in dry run mode or in the plan file
+    fn dedup_range(&mut self, src: BlockLocation, dest: BlockLocation) {
+        if let Some(recorder) = &mut self.recorder {
+            // Ranges deduplicated on previous runs would be skipped by `dedup::dedup` as well
+            let length = dest.length as u64;
+            if !dedup::is_shared(
+                self.backend,
+                &src.path,
+                src.offset,
+                &dest.path,
+                dest.offset,
+                length,
+            ) {
+                recorder.record(&src, &dest);
+            }
+        } else if let Some(plan) = &mut self.plan {
+            plan.write(src, dest);
+        } else {
+            let batches = self.queue.push(src, dest);
+            self.submit(batches);
+        }
+    }
+
+    /// Matches blocks of `scan_result` against previously seen ones and deduplicates them
+    fn dedup_blocks(&mut self, scan_result: &ScanResult<H::Hash>) {
+        let mut merger = RangeMerger::default();
+        // Files are only added to the index if they have blocks not seen before
+        let mut file_id = None;
+
+        for (number, block_hash) in scan_result.block_hashes.iter().enumerate() {
+            match self.block_locations.get(block_hash) {
+                // Both files were already deduplicated on previous runs
+                Some((_, true)) if scan_result.deduplicated => {}
+                Some((x, _)) => {
+                    let block_location = scan_result.get_block_location(number);
+                    if let Some((src, dest)) = merger.push(x, block_location) {
+                        self.dedup_range(src, dest);
+                    }
+                }
+                None => {
+                    let file_id =
+                        *file_id.get_or_insert_with(|| self.block_locations.add_file(scan_result));
+                    self.block_locations.insert(*block_hash, file_id, number);
+                }
+            };
+        }
+
+        if let Some((src, dest)) = merger.finish() {
+            self.dedup_range(src, dest);
+        }
+    }
+
+    /// Deduplicates a group of files with identical contents against one of them
+    fn dedup_whole_files(&mut self, group: &[ScanResult<H::Hash>]) {
+        // Deduplicated files were already deduplicated against each other on previous runs
+        let src = group.iter().find(|r| r.deduplicated).unwrap_or(&group[0]);
+        let dests: Vec<(PathBuf, FileVersion)> = group
+            .iter()
+            .filter(|r| {
+                !(src.deduplicated && r.deduplicated) && (r.dev, r.ino) != (src.dev, src.ino)
+            })
+            .map(|r| (r.path.clone(), r.version()))
+            .collect();
+
+        if dests.is_empty() {
+            return;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            let dests: Vec<&Path> = dests
+                .iter()
+                .map(|(path, _)| path.as_path())
+                .filter(|dest| !dedup::is_shared(self.backend, &src.path, 0, dest, 0, src.size))
+                .collect();
+            recorder.record_files(&src.path, &dests, src.size)
+        } else if let Some(plan) = &mut self.plan {
+            // Plan files only hold ranges, split like the kernel splits whole files
+            for (dest, version) in dests {
+                for offset in (0..src.size).step_by(MAX_DEDUP_LENGTH) {
+                    let length = cmp::min(src.size - offset, MAX_DEDUP_LENGTH as u64) as usize;
+                    plan.write(
+                        BlockLocation {
+                            path: src.path.clone(),
+                            offset,
+                            length,
+                            version: src.version(),
+                        },
+                        BlockLocation {
+                            path: dest.clone(),
+                            offset,
+                            length,
+                            version,
+                        },
+                    );
+                }
+            }
+        } else {
+            self.workers.dedup_files(&src.path, src.version(), dests);
+        }
+    }
+
+    /// Submits all queued ranges and waits for the workers. Returns the report of the run and the
+    /// plan file, if any.
+    fn finish(mut self) -> (RunReport, Option<PlanWriter>) {
+        let batches = self.queue.drain();
+        self.submit(batches);
+
+        let mut report = self.report;
+        report.merge(self.workers.finish());
+        report.dry_run_bytes = self.recorder.as_ref().map(DryRunRecorder::duplicate_bytes);
+        report.dry_run = self.recorder;
+        report.planned = self.plan.as_ref().map(PlanWriter::ops);
+        (report, self.plan)
+    }
+}
+
+/// Builder for a deduplication run over one or more directory trees
+///
+/// ```no_run
+/// let report = fsdedup::Deduplicator::new()
+///     .root("/mnt/data")
+///     .whole_file(true)
+///     .run();
+/// println!("{} bytes deduplicated", report.bytes_deduped);
+/// ```
+pub struct Deduplicator<B = KernelBackend> {
+    roots: Vec<PathBuf>,
+    block_size: usize,
+    dedup_queue: usize,
+    dedup_threads: usize,
+    db: Option<PathBuf>,
+    incremental: bool,
+    whole_file: bool,
+    dry_run: bool,
+    hash: HashAlgorithm,
+    chunking: ChunkingMode,
+    cdc_avg_size: usize,
+    filter: Arc<FileFilter>,
+    memory_limit: Option<usize>,
+    two_phase: bool,
+    source_policy: SourcePolicy,
+    scan_threads: Option<usize>,
+    backend: B,
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self {
+            roots: vec![],
+            block_size: 4096,
+            dedup_queue: 32,
+            dedup_threads: 1,
+            db: None,
+            incremental: false,
+            whole_file: false,
+            dry_run: false,
+            hash: HashAlgorithm::Crc64,
+            chunking: ChunkingMode::Fixed,
+            cdc_avg_size: 65536,
+            filter: Arc::default(),
+            memory_limit: None,
+            two_phase: false,
+            source_policy: SourcePolicy::Oldest,
+            scan_threads: None,
+            backend: KernelBackend::default(),
+        }
+    }
+}
+
+impl Deduplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<B: DedupBackend> Deduplicator<B> {
+    /// Adds a directory tree to deduplicate
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.roots.push(root.into());
+        self
+    }
+
+    /// Adds directory trees to deduplicate
+    pub fn roots(mut self, roots: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.roots.extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    /// Deduplication block size
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Number of scanned files waiting for deduplication
+    pub fn dedup_queue(mut self, dedup_queue: usize) -> Self {
+        self.dedup_queue = dedup_queue;
+        self
+    }
+
+    /// Number of threads making dedupe requests
+    pub fn dedup_threads(mut self, dedup_threads: usize) -> Self {
+        self.dedup_threads = dedup_threads;
+        self
+    }
+
+    /// Hash database, used to skip unchanged files between runs
+    pub fn db(mut self, db: Option<PathBuf>) -> Self {
+        self.db = db;
+        self
+    }
+
+    /// Only scan files changed since the previous run. Needs a hash database.
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Deduplicate identical files as a whole
+    pub fn whole_file(mut self, whole_file: bool) -> Self {
+        self.whole_file = whole_file;
+        self
+    }
+
+    /// Only record what would be deduplicated
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn chunking(mut self, chunking: ChunkingMode) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Average size of content-defined chunks
+    pub fn cdc_avg_size(mut self, cdc_avg_size: usize) -> Self {
+        self.cdc_avg_size = cdc_avg_size;
+        self
+    }
+
+    /// Rules deciding which files are scanned
+    pub fn filter(mut self, filter: FileFilter) -> Self {
+        self.filter = Arc::new(filter);
+        self
+    }
+
+    /// Bytes of block index kept in memory, including its file table. Blocks beyond that are moved
+    /// to temporary files. Not applied in two-phase mode.
+    pub fn memory_limit(mut self, memory_limit: Option<usize>) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Scan everything first, then deduplicate each group of duplicate blocks against a source
+    /// picked by `source_policy`. Keeps all scan results in memory, regardless of `memory_limit`.
+    pub fn two_phase(mut self, two_phase: bool) -> Self {
+        self.two_phase = two_phase;
+        self
+    }
+
+    /// How sources are picked in two-phase mode
+    pub fn source_policy(mut self, source_policy: SourcePolicy) -> Self {
+        self.source_policy = source_policy;
+        self
+    }
+
+    /// Number of threads hashing files, all CPUs by default
+    pub fn scan_threads(mut self, scan_threads: Option<usize>) -> Self {
+        self.scan_threads = scan_threads;
+        self
+    }
+
+    /// Takes all settings present in `config` and adds its roots, others keep their current
+    /// values. The filter is always replaced by the one of `config`.
+    pub fn config(mut self, config: &Config) -> Result<Self, ConfigError> {
+        self.roots.extend(config.roots.iter().cloned());
+        self.block_size = config.block_size.unwrap_or(self.block_size);
+        self.dedup_queue = config.dedup_queue.unwrap_or(self.dedup_queue);
+        self.db = config.db.clone().or(self.db);
+        self.incremental = config.incremental.unwrap_or(self.incremental);
+        self.whole_file = config.whole_file.unwrap_or(self.whole_file);
+        self.hash = config.hash.unwrap_or(self.hash);
+        self.chunking = config.chunking.unwrap_or(self.chunking);
+        self.cdc_avg_size = config.cdc_avg_size.unwrap_or(self.cdc_avg_size);
+        self.two_phase = config.two_phase.unwrap_or(self.two_phase);
+        self.source_policy = config.source_policy.unwrap_or(self.source_policy);
+        self.filter = Arc::new(config.filter.file_filter()?);
+        self.scan_threads = config.limits.scan_threads.or(self.scan_threads);
+        self.dedup_threads = config.limits.dedup_threads.unwrap_or(self.dedup_threads);
+        self.memory_limit = config
+            .limits
+            .memory_limit
+            .map(|mib| mib * 1024 * 1024)
+            .or(self.memory_limit);
+        Ok(self)
+    }
+
+    /// Replaces the backend making dedupe requests
+    pub fn backend<B2: DedupBackend>(self, backend: B2) -> Deduplicator<B2> {
+        Deduplicator {
+            roots: self.roots,
+            block_size: self.block_size,
+            dedup_queue: self.dedup_queue,
+            dedup_threads: self.dedup_threads,
+            db: self.db,
+            incremental: self.incremental,
+            whole_file: self.whole_file,
+            dry_run: self.dry_run,
+            hash: self.hash,
+            chunking: self.chunking,
+            cdc_avg_size: self.cdc_avg_size,
+            filter: self.filter,
+            memory_limit: self.memory_limit,
+            two_phase: self.two_phase,
+            source_policy: self.source_policy,
+            scan_threads: self.scan_threads,
+            backend,
+        }
+    }
+
+    /// Scans all roots and deduplicates them
+    pub fn run(&self) -> RunReport {
+        self.run_hash(RunMode::Dedup).0
+    }
+
+    /// Scans all roots and saves the hashes to the hash database, without deduplicating
+    pub fn scan(&self) -> RunReport {
+        self.run_hash(RunMode::Scan).0
+    }
+
+    /// Scans all roots and writes the dedupe requests to a plan file at `path` instead of making
+    /// them, for `apply_plan` to make later. The hash database is not updated.
+    pub fn write_plan(&self, path: &Path, format: PlanFormat) -> io::Result<RunReport> {
+        let (report, plan) = self.run_hash(RunMode::Plan(PlanWriter::create(path, format)?));
+        plan.unwrap().finish()?;
+        Ok(report)
+    }
+
+    /// Makes the dedupe requests of a plan file. Ranges of files modified since they were
+    /// planned are skipped as stale.
+    pub fn apply_plan(&self, path: &Path) -> io::Result<RunReport> {
+        let start = Instant::now();
+        let reader = PlanReader::open(path)?;
+
+        let mut report = thread::scope(|scope| -> io::Result<RunReport> {
+            let workers = DedupWorkers::new(scope, &self.backend, self.dedup_threads);
+            let mut queue = DedupQueue::default();
+            for op in reader {
+                let op = op?;
+                for (src, dests) in queue.push(op.src, op.dest) {
+                    workers.dedup(src, dests);
+                }
+            }
+            for (src, dests) in queue.drain() {
+                workers.dedup(src, dests);
+            }
+            Ok(workers.finish())
+        })?;
+
+        report.elapsed.total = start.elapsed().as_secs_f64();
+        Ok(report)
+    }
+
+    fn run_hash(&self, mode: RunMode) -> (RunReport, Option<PlanWriter>) {
+        match self.hash {
+            HashAlgorithm::Crc64 => self.run_with::<hash::Crc64>(mode),
+            HashAlgorithm::Xxh3 => self.run_with::<hash::Xxh3>(mode),
+            HashAlgorithm::Blake3 => self.run_with::<hash::Blake3>(mode),
+            HashAlgorithm::Sha256 => self.run_with::<hash::Sha256>(mode),
+        }
+    }
+
+    fn run_with<H: BlockHasher>(&self, mode: RunMode) -> (RunReport, Option<PlanWriter>) {
+        let scan_only = matches!(mode, RunMode::Scan);
+        let plan = match mode {
+            RunMode::Plan(plan) => Some(plan),
+            RunMode::Dedup | RunMode::Scan => None,
+        };
+        let planning = plan.is_some();
+        let start = Instant::now();
+        let options = ScanOptions {
+            block_size: self.block_size,
+            chunking: match self.chunking {
+                ChunkingMode::Fixed => Chunking::Fixed,
+                ChunkingMode::Cdc => Chunking::content_defined(self.block_size, self.cdc_avg_size),
+            },
+        };
+
+        // Roots without dedupe support are still scanned in dry run, to estimate savings, and
+        // when only hashing
+        let roots: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .filter(|root| match self.backend.supports(root) {
+                Ok(true) => true,
+                Ok(false) if self.dry_run || scan_only => true,
+                Ok(false) => {
+                    warn!("Skipping {root:?}, its filesystem does not support deduplication");
+                    false
+                }
+                Err(e) => {
+                    warn!("Could not detect filesystem of {root:?}: {e}");
+                    false
+                }
+            })
+            .cloned()
+            .collect();
+
+        // Scanned paths are absolute
+        let absolute_roots: Vec<PathBuf> = roots
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .collect();
+
+        let mut old_db = match &self.db {
+            Some(db_path) => HashDb::<H>::load(db_path, options).unwrap_or_else(|e| {
+                warn!("Could not load hash database {db_path:?}: {e:?}");
+                HashDb::new(options)
+            }),
+            None => HashDb::new(options),
+        };
+        // Files under other roots aren't scanned by this run, they're kept as they are
+        let other_roots_db = old_db.take_outside(&absolute_roots);
+        let mut new_db = HashDb::<H>::new(options);
+        let load_db_time = start.elapsed();
+
+        // Generations are taken before the scan, so anything written during it is picked up next time
+        if self.incremental {
+            for root in &roots {
+                match fs::canonicalize(root).and_then(|root| {
+                    incremental::subvolume_generation(&root).map(|generation| (root, generation))
+                }) {
+                    Ok((root, generation)) => new_db.set_generation(root, generation),
+                    Err(e) => warn!("Could not get subvolume generation of {root:?}: {e}"),
+                }
+            }
+        }
+
+        let (mut report, plan) = thread::scope(|scope| {
+            let mut deduper = Deduper::<H, B>::new(
+                &self.backend,
+                DedupWorkers::new(scope, &self.backend, self.dedup_threads),
+                (self.dry_run && !planning).then(|| DryRunRecorder::new(self.block_size)),
+                plan,
+                self.memory_limit,
+            );
+            // Only used in whole file mode
+            let mut whole_files = FileGroups::<H::Hash>::new();
+            // Only used in two-phase mode
+            let mut planned_files = vec![];
+
+            let (scanned_tx, scanned_rx) = mpsc::sync_channel(self.dedup_queue);
+
+            // Crawlers in thread pool
+            let scan_start = Instant::now();
+            let incremental = self.incremental;
+            let filter = self.filter.clone();
+            let pool = self.scan_threads.and_then(|threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| warn!("Could not start {threads} scan threads: {e}"))
+                    .ok()
+            });
+            let crawler_handle = thread::spawn(move || {
+                let crawl = || {
+                    scan::crawl_paths(&roots, &filter, &options, &old_db, incremental, scanned_tx)
+                };
+                match pool {
+                    Some(pool) => pool.install(crawl),
+                    None => crawl(),
+                }
+            });
+
+            // Main thread: matching, dedupe requests go to the workers
+            while let Ok(scan_result) = scanned_rx.recv() {
+                if scan_result.cached {
+                    deduper.report.files_cached += 1;
+                } else {
+                    deduper.report.files_scanned += 1;
+                    deduper.report.bytes_hashed += scan_result.size;
+                }
+                deduper.report.hard_links += scan_result.links.len() as u64;
+
+                if scan_only {
+                    new_db.insert(scan_result);
+                } else if self.whole_file && scan_result.size > 0 {
+                    whole_files
+                        .entry((scan_result.size, scan_result.file_hash))
+                        .or_default()
+                        .push(scan_result);
+                } else if self.two_phase {
+                    planned_files.push(scan_result);
+                } else {
+                    deduper.dedup_blocks(&scan_result);
+                    new_db.insert(scan_result);
+                }
+            }
+
+            let _ = crawler_handle.join();
+            deduper.report.elapsed.scan = scan_start.elapsed().as_secs_f64();
+
+            // Files without identical copies still get deduplicated block by block
+            for mut group in whole_files.into_values() {
+                if group.len() > 1 {
+                    deduper.dedup_whole_files(&group);
+                } else if self.two_phase {
+                    planned_files.append(&mut group);
+                } else {
+                    deduper.dedup_blocks(&group[0]);
+                }
+
+                for scan_result in group {
+                    new_db.insert(scan_result);
+                }
+            }
+
+            if self.two_phase {
+                let plan = RunReport::time(&mut deduper.report.elapsed.plan, || {
+                    plan::plan_blocks(&planned_files, self.source_policy, &self.backend)
+                });
+                for (src, dest) in plan {
+                    deduper.dedup_range(src, dest);
+                }
+
+                for scan_result in planned_files {
+                    new_db.insert(scan_result);
+                }
+            }
+
+            deduper.finish()
+        });
+
+        // Only files deduplicated by this run are skipped next time, unless they are modified
+        if !self.dry_run && !planning && !scan_only {
+            new_db.mark_deduplicated(&report.failed_dests);
+        }
+        new_db.extend(other_roots_db);
+        if let Some(db_path) = self.db.as_ref().filter(|_| !self.dry_run && !planning) {
+            let save_start = Instant::now();
+            if let Err(e) = new_db.save(db_path) {
+                warn!("Could not save hash database {db_path:?}: {e:?}");
+            }
+            report.elapsed.save_db = save_start.elapsed().as_secs_f64();
+        }
+
+        report.elapsed.load_db = load_db_time.as_secs_f64();
+        report.elapsed.total = start.elapsed().as_secs_f64();
+        (report, plan)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::dedup::BlockLocation;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Number of file pairs shown in the report
+const TOP_PAIRS: usize = 10;
+
+/// Records deduplication candidates instead of submitting them to the kernel
+#[derive(Debug)]
+pub struct DryRunRecorder {
+    block_size: usize,
+    duplicate_blocks: u64,
+    duplicate_bytes: u64,
+    /// Shared bytes between (source, destination) files
+    pairs: HashMap<(PathBuf, PathBuf), u64>,
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+impl DryRunRecorder {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            duplicate_blocks: 0,
+            duplicate_bytes: 0,
+            pairs: HashMap::new(),
+        }
+    }
+
+    fn record_pair(&mut self, src: &Path, dest: &Path, length: u64) {
+        self.duplicate_blocks += length.div_ceil(self.block_size as u64);
+        self.duplicate_bytes += length;
+        *self
+            .pairs
+            .entry((src.to_path_buf(), dest.to_path_buf()))
+            .or_default() += length;
+    }
+
+    /// Records a range, that would be deduplicated with `dedup::dedup`
+    pub fn record(&mut self, src: &BlockLocation, dest: &BlockLocation) {
+        self.record_pair(&src.path, &dest.path, dest.length as u64);
+    }
+
+    /// Records files, that would be deduplicated with `dedup::dedup_files`
+    pub fn record_files(&mut self, src: &Path, dests: &[&Path], size: u64) {
+        for dest in dests {
+            self.record_pair(src, dest, size);
+        }
+    }
+
+    /// Bytes that would be reclaimed
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.duplicate_bytes
+    }
+
+    pub fn print_report(&self) {
+        println!("Dry run, nothing was deduplicated");
+        println!("Duplicate blocks: {}", self.duplicate_blocks);
+        println!(
+            "Bytes that would be reclaimed: {} ({})",
+            self.duplicate_bytes,
+            format_bytes(self.duplicate_bytes)
+        );
+
+        let mut pairs: Vec<_> = self.pairs.iter().collect();
+        pairs.sort_unstable_by(|a, b| b.1.cmp(a.1));
+
+        if !pairs.is_empty() {
+            println!("Top file pairs by shared bytes:");
+        }
+        for ((src, dest), bytes) in pairs.into_iter().take(TOP_PAIRS) {
+            println!("  {:>12}  {src:?} <- {dest:?}", format_bytes(*bytes));
+        }
+    }
+}
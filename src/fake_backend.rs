@@ -0,0 +1,192 @@
+//! Backend for tests, which simulates shared extents in memory on any filesystem
+
+use crate::backend::{DedupBackend, DedupeDest, DedupeStatus};
+use crate::dedup::MAX_DEDUP_DESTINATIONS;
+use crate::fiemap::PhysicalRange;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::{fs, io};
+
+#[derive(Default)]
+struct FakeState {
+    /// Physical block of each (inode, logical block), assigned on first use
+    blocks: HashMap<(u64, u64), u64>,
+    next_physical: u64,
+    requests: usize,
+    /// Number of destinations of each request
+    request_sizes: Vec<usize>,
+    physical_queries: usize,
+    /// Inodes whose dedupe fails with `EPERM`
+    failing: HashSet<u64>,
+    /// Threads which made requests into each destination inode
+    dest_threads: HashMap<u64, HashSet<thread::ThreadId>>,
+}
+
+impl FakeState {
+    fn physical(&mut self, ino: u64, block: u64) -> u64 {
+        let next_physical = &mut self.next_physical;
+        *self.blocks.entry((ino, block)).or_insert_with(|| {
+            *next_physical += 1;
+            *next_physical
+        })
+    }
+}
+
+/// Keeps track of which blocks share storage instead of asking the filesystem. File contents
+/// are compared, but never modified. Clones share their state.
+#[derive(Clone)]
+pub struct FakeBackend {
+    block_size: u64,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeBackend {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            block_size: block_size as u64,
+            state: Arc::default(),
+        }
+    }
+
+    /// Number of dedupe requests made so far
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Number of destinations of each dedupe request made so far
+    pub fn request_sizes(&self) -> Vec<usize> {
+        self.state.lock().unwrap().request_sizes.clone()
+    }
+
+    /// Makes deduplication into the file at `path` fail, like for an immutable file
+    pub fn fail_dedupe(&self, path: &Path) {
+        let ino = fs::metadata(path).unwrap().ino();
+        self.state.lock().unwrap().failing.insert(ino);
+    }
+
+    /// Number of threads which made dedupe requests into the file at `path`
+    pub fn dest_threads(&self, path: &Path) -> usize {
+        let ino = fs::metadata(path).unwrap().ino();
+        let state = self.state.lock().unwrap();
+        state.dest_threads.get(&ino).map_or(0, HashSet::len)
+    }
+
+    /// Number of physical range lookups made so far
+    pub fn physical_queries(&self) -> usize {
+        self.state.lock().unwrap().physical_queries
+    }
+
+    /// Returns true if both ranges share storage
+    pub fn is_shared_path(
+        &self,
+        path1: &Path,
+        offset1: u64,
+        path2: &Path,
+        offset2: u64,
+        length: u64,
+    ) -> bool {
+        let file1 = fs::File::open(path1).unwrap();
+        let file2 = fs::File::open(path2).unwrap();
+        self.is_shared(&file1, offset1, &file2, offset2, length)
+    }
+}
+
+impl DedupBackend for FakeBackend {
+    fn supports(&self, _root: &Path) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn dedupe(
+        &self,
+        src: &fs::File,
+        src_offset: u64,
+        length: u64,
+        dests: &mut [DedupeDest],
+    ) -> Result<(), String> {
+        let src_metadata = src.metadata().map_err(|e| e.to_string())?;
+        // Like the kernel, only whole blocks or ranges ending at the end of the source
+        let aligned = |offset: u64| offset.is_multiple_of(self.block_size);
+        if !aligned(src_offset)
+            || !(aligned(length) || src_offset + length == src_metadata.len())
+            || dests.iter().any(|dest| !aligned(dest.offset))
+            || dests.len() > MAX_DEDUP_DESTINATIONS
+        {
+            return Err("Invalid argument".to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        state.request_sizes.push(dests.len());
+
+        for dest in dests {
+            let dest_ino = dest.file.metadata().map_err(|e| e.to_string())?.ino();
+            state
+                .dest_threads
+                .entry(dest_ino)
+                .or_default()
+                .insert(thread::current().id());
+            if state.failing.contains(&dest_ino) {
+                dest.status = DedupeStatus::Error(libc::EPERM);
+                continue;
+            }
+            match self.compare(src, src_offset, dest.file, dest.offset, length) {
+                Ok(true) => {}
+                Ok(false) => {
+                    dest.status = DedupeStatus::Differs;
+                    continue;
+                }
+                Err(e) => {
+                    dest.status = DedupeStatus::Error(e.raw_os_error().unwrap_or(libc::EIO));
+                    continue;
+                }
+            }
+
+            let src_block = src_offset / self.block_size;
+            let dest_block = dest.offset / self.block_size;
+            for block in 0..length.div_ceil(self.block_size) {
+                let physical = state.physical(src_metadata.ino(), src_block + block);
+                state
+                    .blocks
+                    .insert((dest_ino, dest_block + block), physical);
+            }
+            dest.bytes_deduped = length;
+            dest.status = DedupeStatus::Same;
+        }
+        Ok(())
+    }
+
+    fn physical_ranges(
+        &self,
+        file: &fs::File,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<Option<Vec<PhysicalRange>>> {
+        let metadata = file.metadata()?;
+        if offset + length > metadata.len() {
+            return Ok(None);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.physical_queries += 1;
+        let mut ranges: Vec<PhysicalRange> = vec![];
+        let end = offset + length;
+        let mut position = offset;
+        while position < end {
+            let block = position / self.block_size;
+            let block_offset = position % self.block_size;
+            let physical = state.physical(metadata.ino(), block) * self.block_size + block_offset;
+            let length = (self.block_size - block_offset).min(end - position);
+
+            match ranges.last_mut() {
+                Some(last) if last.physical + last.length == physical => last.length += length,
+                _ => ranges.push(PhysicalRange { physical, length }),
+            }
+            position += length;
+        }
+
+        Ok(Some(ranges))
+    }
+}
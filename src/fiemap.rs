@@ -0,0 +1,113 @@
+//! Physical extent mapping of files, used to detect ranges that already share storage
+
+use std::os::unix::io::AsRawFd;
+use std::{fs, io};
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x0000_0004;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x0000_0008;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x0000_0200;
+
+/// Extents with these flags don't have a comparable physical address
+const FIEMAP_EXTENT_INCOMPARABLE: u32 = FIEMAP_EXTENT_UNKNOWN
+    | FIEMAP_EXTENT_DELALLOC
+    | FIEMAP_EXTENT_ENCODED
+    | FIEMAP_EXTENT_DATA_INLINE;
+
+/// Number of extents requested with a single ioctl
+const EXTENT_BATCH: usize = 64;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; EXTENT_BATCH],
+}
+
+/// Contiguous piece of a file range, stored at `physical`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PhysicalRange {
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// Maps `length` bytes of `file` at `offset` to physical ranges. Returns `None` if some part of
+/// the range has no comparable physical address, like holes, inline or compressed extents.
+pub fn physical_ranges(
+    file: &fs::File,
+    offset: u64,
+    length: u64,
+) -> io::Result<Option<Vec<PhysicalRange>>> {
+    let end = offset + length;
+    let mut position = offset;
+    let mut ranges: Vec<PhysicalRange> = vec![];
+
+    while position < end {
+        let mut fiemap = Fiemap {
+            fm_start: position,
+            fm_length: end - position,
+            fm_flags: 0,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENT_BATCH as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); EXTENT_BATCH],
+        };
+
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if fiemap.fm_mapped_extents == 0 {
+            return Ok(None);
+        }
+
+        for extent in &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize] {
+            let extent_end = extent.fe_logical + extent.fe_length;
+            if extent.fe_logical > position
+                || extent_end <= position
+                || extent.fe_flags & FIEMAP_EXTENT_INCOMPARABLE != 0
+            {
+                return Ok(None);
+            }
+
+            let physical = extent.fe_physical + (position - extent.fe_logical);
+            let length = extent_end.min(end) - position;
+
+            // Adjacent extents may be contiguous on disk as well
+            match ranges.last_mut() {
+                Some(last) if last.physical + last.length == physical => last.length += length,
+                _ => ranges.push(PhysicalRange { physical, length }),
+            }
+            position += length;
+
+            if extent.fe_flags & FIEMAP_EXTENT_LAST != 0 || position >= end {
+                break;
+            }
+        }
+
+        let last_extent = fiemap.fm_extents[fiemap.fm_mapped_extents as usize - 1];
+        if last_extent.fe_flags & FIEMAP_EXTENT_LAST != 0 && position < end {
+            // File ends before the range does
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(ranges))
+}
@@ -0,0 +1,203 @@
+//! Include/exclude rules deciding which files are scanned
+//!
+//! Glob patterns are matched against the file name and against the path relative to the root,
+//! so `*.sqlite-wal` matches anywhere, while `cache/*.bin` only matches below the root. A `*`
+//! never matches `/`, use `**` for that.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fmt, fs, io};
+use tracing::warn;
+
+#[derive(Debug)]
+pub enum FilterError {
+    Glob(globset::Error),
+    Regex(regex::Error),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Glob(e) => e.fmt(f),
+            Self::Regex(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<globset::Error> for FilterError {
+    fn from(e: globset::Error) -> Self {
+        Self::Glob(e)
+    }
+}
+
+impl From<regex::Error> for FilterError {
+    fn from(e: regex::Error) -> Self {
+        Self::Regex(e)
+    }
+}
+
+/// Decides which files under a root are scanned. The default filter accepts everything.
+#[derive(Default)]
+pub struct FileFilter {
+    /// If present, only matching files are scanned
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    /// Matched against the path relative to the root
+    exclude_regex: Option<RegexSet>,
+    /// Names of gitignore-style files honored in every directory
+    ignore_files: Vec<String>,
+    /// Rules from ignore files of each directory seen so far
+    ignore_rules: Mutex<HashMap<PathBuf, Gitignore>>,
+    /// Files smaller than this are skipped
+    min_size: u64,
+    /// Files larger than this are skipped
+    max_size: Option<u64>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, FilterError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+fn glob_matches(set: &GlobSet, relative: &Path) -> bool {
+    relative.file_name().is_some_and(|name| set.is_match(name)) || set.is_match(relative)
+}
+
+impl FileFilter {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        exclude_regex: &[String],
+        ignore_files: &[String],
+    ) -> Result<Self, FilterError> {
+        Ok(Self {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            exclude_regex: match exclude_regex {
+                [] => None,
+                patterns => Some(RegexSet::new(patterns)?),
+            },
+            ignore_files: ignore_files.to_vec(),
+            ignore_rules: Mutex::default(),
+            min_size: 0,
+            max_size: None,
+        })
+    }
+
+    /// Skips files smaller than `min_size` or larger than `max_size` bytes
+    pub fn size_limits(mut self, min_size: u64, max_size: Option<u64>) -> Self {
+        self.min_size = min_size;
+        self.max_size = max_size;
+        self
+    }
+
+    /// Returns true if files of `size` bytes are within the size limits
+    pub fn accepts_size(&self, size: u64) -> bool {
+        size >= self.min_size && self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+
+    /// Reads patterns from `path`, one per line. Empty lines and lines starting with `#` are
+    /// skipped.
+    pub fn read_patterns(path: &Path) -> io::Result<Vec<String>> {
+        Ok(fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect())
+    }
+
+    fn is_excluded_path(&self, relative: &Path) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|exclude| glob_matches(exclude, relative))
+            || self
+                .exclude_regex
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(&relative.to_string_lossy()))
+    }
+
+    /// Returns true if `path` is excluded by ignore files in any directory from `root` down
+    fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        if self.ignore_files.is_empty() {
+            return false;
+        }
+
+        let dirs: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root))
+            .collect();
+
+        let mut ignore_rules = self.ignore_rules.lock().unwrap();
+        let mut ignored = false;
+        // Rules closer to the file take precedence
+        for dir in dirs.into_iter().rev() {
+            let rules = ignore_rules
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| self.load_ignore_files(dir));
+            let matched = rules.matched_path_or_any_parents(path, is_dir);
+            if !matched.is_none() {
+                ignored = matched.is_ignore();
+            }
+        }
+        ignored
+    }
+
+    fn load_ignore_files(&self, dir: &Path) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(dir);
+        for name in &self.ignore_files {
+            let ignore_file = dir.join(name);
+            if ignore_file.is_file() {
+                if let Some(e) = builder.add(&ignore_file) {
+                    warn!("Could not read {ignore_file:?}: {e}");
+                }
+            }
+        }
+
+        builder.build().unwrap_or_else(|e| {
+            warn!("Invalid ignore files in {dir:?}: {e}");
+            Gitignore::empty()
+        })
+    }
+
+    /// Returns true if `path` under `root` must not be scanned. Directories are only checked
+    /// against exclude rules, so excluded trees can be skipped while walking.
+    pub fn is_excluded(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return false,
+        };
+
+        // Excluding a directory excludes everything below it
+        if relative
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| self.is_excluded_path(dir))
+        {
+            return true;
+        }
+
+        if !is_dir
+            && self
+                .include
+                .as_ref()
+                .is_some_and(|include| !glob_matches(include, relative))
+        {
+            return true;
+        }
+
+        self.is_ignored(root, path, is_dir)
+    }
+}
@@ -0,0 +1,98 @@
+use clap::ArgEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fmt::Debug;
+
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    /// 64-bit CRC. Fast, but collides noticeably on large datasets
+    Crc64,
+    /// 128-bit xxHash3
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+/// Streaming hash function used for block and file contents
+pub trait BlockHasher: Default + 'static {
+    type Hash: Copy
+        + Eq
+        + std::hash::Hash
+        + Debug
+        + Serialize
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static;
+
+    const ALGORITHM: HashAlgorithm;
+
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Self::Hash;
+}
+
+#[derive(Default)]
+pub struct Crc64(crc64fast::Digest);
+
+impl BlockHasher for Crc64 {
+    type Hash = u64;
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Crc64;
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.write(data);
+    }
+
+    fn finish(self) -> Self::Hash {
+        self.0.sum64()
+    }
+}
+
+#[derive(Default)]
+pub struct Xxh3(xxhash_rust::xxh3::Xxh3);
+
+impl BlockHasher for Xxh3 {
+    type Hash = u128;
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Xxh3;
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> Self::Hash {
+        self.0.digest128()
+    }
+}
+
+#[derive(Default)]
+pub struct Blake3(blake3::Hasher);
+
+impl BlockHasher for Blake3 {
+    type Hash = [u8; 32];
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> Self::Hash {
+        self.0.finalize().into()
+    }
+}
+
+#[derive(Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl BlockHasher for Sha256 {
+    type Hash = [u8; 32];
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> Self::Hash {
+        self.0.finalize().into()
+    }
+}
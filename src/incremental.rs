@@ -0,0 +1,335 @@
+//! Enumeration of files changed since a given btrfs transaction generation.
+//!
+//! This is the same approach as `btrfs subvolume find-new`: file extent items are searched in the
+//! subvolume tree, skipping tree leaves not modified since the last run, and the owning inodes
+//! are resolved back to paths. Inode refs in modified leaves are included too, so renamed files
+//! and new hard links are found.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::{fs, io, mem};
+use tracing::debug;
+
+const BTRFS_IOC_TREE_SEARCH: libc::c_ulong = 0xd000_9411;
+const BTRFS_IOC_INO_LOOKUP: libc::c_ulong = 0xd000_9412;
+
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+const BTRFS_INODE_REF_KEY: u32 = 12;
+const BTRFS_EXTENT_DATA_KEY: u32 = 108;
+const BTRFS_ROOT_ITEM_KEY: u32 = 132;
+const BTRFS_ROOT_REF_KEY: u32 = 156;
+
+/// Offset of `generation` in `struct btrfs_root_item`, right after the embedded inode item
+const ROOT_ITEM_GENERATION_OFFSET: usize = 160;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; 4096 - mem::size_of::<SearchKey>()],
+}
+
+#[repr(C)]
+struct SearchHeader {
+    transid: u64,
+    objectid: u64,
+    offset: u64,
+    item_type: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; 4080],
+}
+
+/// Single item returned by tree search
+struct SearchItem<'a> {
+    objectid: u64,
+    offset: u64,
+    item_type: u32,
+    data: &'a [u8],
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Runs tree search until `key` range is exhausted, calling `f` for every found item
+fn tree_search(
+    file: &fs::File,
+    mut key: SearchKey,
+    mut f: impl FnMut(SearchItem),
+) -> io::Result<()> {
+    loop {
+        let mut args = SearchArgs {
+            key: SearchKey {
+                nr_items: 4096,
+                ..key
+            },
+            buf: [0; 4096 - mem::size_of::<SearchKey>()],
+        };
+
+        if unsafe { libc::ioctl(file.as_raw_fd(), BTRFS_IOC_TREE_SEARCH as _, &mut args) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if args.key.nr_items == 0 {
+            break;
+        }
+
+        let mut pos = 0;
+        let mut last = (0, 0, 0);
+        for _ in 0..args.key.nr_items {
+            let header: SearchHeader =
+                unsafe { std::ptr::read_unaligned(args.buf[pos..].as_ptr() as *const _) };
+            pos += mem::size_of::<SearchHeader>();
+            f(SearchItem {
+                objectid: header.objectid,
+                offset: header.offset,
+                item_type: header.item_type,
+                data: &args.buf[pos..pos + header.len as usize],
+            });
+            pos += header.len as usize;
+            last = (header.objectid, header.item_type, header.offset);
+        }
+
+        // Continue right after the last returned key
+        let (objectid, item_type, offset) = last;
+        key.min_objectid = objectid;
+        key.min_type = item_type;
+        if offset < u64::MAX {
+            key.min_offset = offset + 1;
+        } else if item_type < u8::MAX as u32 {
+            key.min_type = item_type + 1;
+            key.min_offset = 0;
+        } else if objectid < u64::MAX {
+            key.min_objectid = objectid + 1;
+            key.min_type = 0;
+            key.min_offset = 0;
+        } else {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves directory `ino` into a path relative to the subvolume root, with trailing slash
+fn ino_lookup(file: &fs::File, ino: u64) -> io::Result<PathBuf> {
+    let mut args = InoLookupArgs {
+        treeid: 0,
+        objectid: ino,
+        name: [0; 4080],
+    };
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), BTRFS_IOC_INO_LOOKUP as _, &mut args) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let len = args
+        .name
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(args.name.len());
+    Ok(PathBuf::from(OsStr::from_bytes(&args.name[..len])))
+}
+
+/// Returns id of the subvolume `file` belongs to
+fn subvolume_id(file: &fs::File) -> io::Result<u64> {
+    let mut args = InoLookupArgs {
+        treeid: 0,
+        objectid: BTRFS_FIRST_FREE_OBJECTID,
+        name: [0; 4080],
+    };
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), BTRFS_IOC_INO_LOOKUP as _, &mut args) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(args.treeid)
+}
+
+/// Returns current transaction generation of the subvolume containing `path`
+pub fn subvolume_generation(path: &Path) -> io::Result<u64> {
+    let file = fs::File::open(path)?;
+    let root_id = subvolume_id(&file)?;
+
+    let mut generation = None;
+    tree_search(
+        &file,
+        SearchKey {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            min_objectid: root_id,
+            max_objectid: root_id,
+            min_type: BTRFS_ROOT_ITEM_KEY,
+            max_type: BTRFS_ROOT_ITEM_KEY,
+            max_offset: u64::MAX,
+            max_transid: u64::MAX,
+            ..Default::default()
+        },
+        |item| {
+            if item.item_type == BTRFS_ROOT_ITEM_KEY {
+                generation = generation.max(read_u64(item.data, ROOT_ITEM_GENERATION_OFFSET));
+            }
+        },
+    )?;
+
+    generation
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "subvolume root item not found"))
+}
+
+/// Whether subvolume `root_id` contains other subvolumes, which are not searched with it
+fn has_nested_subvolumes(file: &fs::File, root_id: u64) -> io::Result<bool> {
+    let mut nested = false;
+    tree_search(
+        file,
+        SearchKey {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            min_objectid: root_id,
+            max_objectid: root_id,
+            min_type: BTRFS_ROOT_REF_KEY,
+            max_type: BTRFS_ROOT_REF_KEY,
+            max_offset: u64::MAX,
+            max_transid: u64::MAX,
+            ..Default::default()
+        },
+        |item| nested |= item.item_type == BTRFS_ROOT_REF_KEY,
+    )?;
+    Ok(nested)
+}
+
+/// Resolves file `ino` into a path relative to the subvolume root using its first inode ref
+fn inode_path(file: &fs::File, ino: u64) -> io::Result<Option<PathBuf>> {
+    // (parent directory inode, name) of the first reference
+    let mut reference = None;
+    tree_search(
+        file,
+        SearchKey {
+            min_objectid: ino,
+            max_objectid: ino,
+            min_type: BTRFS_INODE_REF_KEY,
+            max_type: BTRFS_INODE_REF_KEY,
+            max_offset: u64::MAX,
+            max_transid: u64::MAX,
+            ..Default::default()
+        },
+        |item| {
+            if item.item_type != BTRFS_INODE_REF_KEY || reference.is_some() {
+                return;
+            }
+            // struct btrfs_inode_ref { __le64 index; __le16 name_len; char name[]; }
+            if let Some(name_len) = read_u16(item.data, 8) {
+                if let Some(name) = item.data.get(10..10 + name_len as usize) {
+                    reference = Some((item.offset, OsStr::from_bytes(name).to_owned()));
+                }
+            }
+        },
+    )?;
+
+    match reference {
+        Some((parent, name)) => Ok(Some(ino_lookup(file, parent)?.join(name))),
+        None => Ok(None),
+    }
+}
+
+/// Lists regular files under `root` with data written, or linked or renamed, after `generation`.
+/// Fails if the subvolume of `root` contains nested subvolumes.
+pub fn find_new(root: &Path, generation: u64) -> io::Result<Vec<PathBuf>> {
+    let file = fs::File::open(root)?;
+    if has_nested_subvolumes(&file, subvolume_id(&file)?)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "nested subvolumes are not searched",
+        ));
+    }
+    let root_ino = file.metadata()?.ino();
+    let root_prefix = if root_ino == BTRFS_FIRST_FREE_OBJECTID {
+        PathBuf::new()
+    } else {
+        ino_lookup(&file, root_ino)?
+    };
+
+    let mut changed_inodes = BTreeSet::new();
+    tree_search(
+        &file,
+        SearchKey {
+            max_objectid: u64::MAX,
+            max_offset: u64::MAX,
+            min_transid: generation + 1,
+            max_transid: u64::MAX,
+            min_type: BTRFS_INODE_REF_KEY,
+            max_type: BTRFS_EXTENT_DATA_KEY,
+            ..Default::default()
+        },
+        |item| match item.item_type {
+            // Leaves are filtered by transid, but individual extents in them may still be old.
+            // struct btrfs_file_extent_item starts with __le64 generation.
+            BTRFS_EXTENT_DATA_KEY if read_u64(item.data, 0).is_some_and(|gen| gen > generation) => {
+                changed_inodes.insert(item.objectid);
+            }
+            // Inode refs have no generation, unchanged files sharing a leaf with new refs are
+            // looked up in the hash database again
+            BTRFS_INODE_REF_KEY => {
+                changed_inodes.insert(item.objectid);
+            }
+            _ => {}
+        },
+    )?;
+    debug!(
+        "{} inodes changed in {:?} since generation {generation}",
+        changed_inodes.len(),
+        root
+    );
+
+    let mut paths = vec![];
+    for ino in changed_inodes {
+        let relative_path = match inode_path(&file, ino)? {
+            Some(path) => path,
+            None => continue,
+        };
+        let path = match relative_path.strip_prefix(&root_prefix) {
+            Ok(path) => root.join(path),
+            // Outside of requested root
+            Err(_) => continue,
+        };
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
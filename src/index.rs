@@ -0,0 +1,356 @@
+//! Compact index of block hashes to the location they were first seen at
+//!
+//! Files are interned in a table, so each block only takes a hash and a `(file_id, block_index)`
+//! pair. When the index exceeds the memory limit, blocks are written to a sorted run on disk,
+//! which is then binary searched. Each run has a bloom filter in memory, so most lookups of new
+//! hashes don't read from disk, and runs of similar size are merged, so there are few of them.
+
+use crate::dedup::BlockLocation;
+use crate::hash::BlockHasher;
+use crate::scan::ScanResult;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::{fs, io, mem};
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Bits of the bloom filter per record, for about 1% false positives
+const BLOOM_BITS_PER_RECORD: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockRef {
+    file_id: u32,
+    block_index: u32,
+}
+
+impl BlockRef {
+    fn encode(&self, record: &mut Vec<u8>) {
+        record.extend(self.file_id.to_le_bytes());
+        record.extend(self.block_index.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            file_id: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            block_index: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+}
+
+/// Set of encoded hashes, which may have false positives
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new(records: u64) -> Self {
+        let words = (records as usize * BLOOM_BITS_PER_RECORD)
+            .div_ceil(64)
+            .max(1);
+        Self {
+            bits: vec![0; words],
+        }
+    }
+
+    /// Bit positions of `key`, using double hashing
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = xxh3_128(key);
+        let (hash1, hash2) = (hash as u64, (hash >> 64) as u64 | 1);
+        let bits = self.bits.len() as u64 * 64;
+        (0..BLOOM_HASHES).map(move |i| (hash1.wrapping_add(i.wrapping_mul(hash2)) % bits) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for position in self.positions(key).collect::<Vec<_>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.bits.len() * mem::size_of::<u64>()
+    }
+}
+
+/// Fixed size records of an encoded hash followed by a `BlockRef`, sorted by hash
+struct SpillRun {
+    file: fs::File,
+    records: u64,
+    record_size: usize,
+    filter: BloomFilter,
+}
+
+/// Writes sorted records into a new `SpillRun`
+struct RunWriter {
+    writer: BufWriter<fs::File>,
+    records: u64,
+    record_size: usize,
+    filter: BloomFilter,
+}
+
+impl RunWriter {
+    /// Writer of a run with up to `records` records
+    fn new(records: u64, record_size: usize) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(tempfile::tempfile()?),
+            records: 0,
+            record_size,
+            filter: BloomFilter::new(records),
+        })
+    }
+
+    fn push(&mut self, record: &[u8]) -> io::Result<()> {
+        self.filter
+            .insert(&record[..self.record_size - mem::size_of::<BlockRef>()]);
+        self.records += 1;
+        self.writer.write_all(record)
+    }
+
+    fn finish(self) -> io::Result<SpillRun> {
+        Ok(SpillRun {
+            file: self.writer.into_inner().map_err(|e| e.into_error())?,
+            records: self.records,
+            record_size: self.record_size,
+            filter: self.filter,
+        })
+    }
+}
+
+/// Reads the records of a `SpillRun` in order
+struct RunReader<'a> {
+    reader: BufReader<&'a fs::File>,
+    remaining: u64,
+    record_size: usize,
+}
+
+impl<'a> RunReader<'a> {
+    fn new(run: &'a SpillRun) -> io::Result<Self> {
+        let mut file = &run.file;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            remaining: run.records,
+            record_size: run.record_size,
+        })
+    }
+
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let mut record = vec![0; self.record_size];
+        self.reader.read_exact(&mut record)?;
+        Ok(Some(record))
+    }
+}
+
+impl SpillRun {
+    /// Writes `records`, which must be sorted, into a new run
+    fn write(records: &[Vec<u8>]) -> io::Result<Self> {
+        let mut writer = RunWriter::new(records.len() as u64, records[0].len())?;
+        for record in records {
+            writer.push(record)?;
+        }
+        writer.finish()
+    }
+
+    /// Merges two runs without common hashes into a new one
+    fn merge(first: &SpillRun, second: &SpillRun) -> io::Result<Self> {
+        let mut writer = RunWriter::new(first.records + second.records, first.record_size)?;
+        let mut first = RunReader::new(first)?;
+        let mut second = RunReader::new(second)?;
+
+        let (mut left, mut right) = (first.next()?, second.next()?);
+        while left.is_some() || right.is_some() {
+            let take_left = match (&left, &right) {
+                (Some(left), Some(right)) => left < right,
+                (left, _) => left.is_some(),
+            };
+            if take_left {
+                writer.push(&left.unwrap())?;
+                left = first.next()?;
+            } else {
+                writer.push(&right.unwrap())?;
+                right = second.next()?;
+            }
+        }
+        writer.finish()
+    }
+
+    fn find(&self, key: &[u8]) -> io::Result<Option<BlockRef>> {
+        if !self.filter.contains(key) {
+            return Ok(None);
+        }
+
+        let mut record = vec![0; self.record_size];
+        let (mut low, mut high) = (0, self.records);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            self.file
+                .read_exact_at(&mut record, middle * self.record_size as u64)?;
+            match record[..key.len()].cmp(key) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(Some(BlockRef::decode(&record[key.len()..]))),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// First seen location of each block hash, limited to `memory_limit` bytes in memory
+pub struct BlockIndex<H: BlockHasher> {
+    /// Scan results without block hashes, by file id
+    files: Vec<ScanResult<H::Hash>>,
+    /// Estimated memory used by `files`
+    files_memory: usize,
+    blocks: HashMap<H::Hash, BlockRef>,
+    memory_limit: Option<usize>,
+    /// Runs on disk, with decreasing number of records
+    spilled: Vec<SpillRun>,
+}
+
+fn encode_hash<H: serde::Serialize>(hash: &H) -> Vec<u8> {
+    bincode::serialize(hash).unwrap()
+}
+
+impl<H: BlockHasher> BlockIndex<H> {
+    pub fn new(memory_limit: Option<usize>) -> Self {
+        Self {
+            files: vec![],
+            files_memory: 0,
+            blocks: HashMap::new(),
+            memory_limit,
+            spilled: vec![],
+        }
+    }
+
+    /// Adds the file of `scan_result` to the file table and returns its id
+    pub fn add_file(&mut self, scan_result: &ScanResult<H::Hash>) -> u32 {
+        let file_id = self.files.len().try_into().expect("too many files");
+        let file = scan_result.without_hashes();
+        self.files_memory += mem::size_of_val(&file)
+            + file.path.as_os_str().len()
+            + file
+                .chunk_offsets
+                .as_ref()
+                .map_or(0, |offsets| mem::size_of_val(offsets.as_slice()));
+        self.files.push(file);
+        file_id
+    }
+
+    /// Returns the location of `hash` and true, if its file was deduplicated on a previous run
+    pub fn get(&self, hash: &H::Hash) -> Option<(BlockLocation, bool)> {
+        let block = match self.blocks.get(hash) {
+            Some(block) => *block,
+            None => match self.find_spilled(&encode_hash(hash)) {
+                Ok(block) => block?,
+                Err(e) => {
+                    warn!("Could not read block index from disk: {e}");
+                    return None;
+                }
+            },
+        };
+
+        let file = &self.files[block.file_id as usize];
+        Some((
+            file.get_block_location(block.block_index as usize),
+            file.deduplicated,
+        ))
+    }
+
+    fn find_spilled(&self, key: &[u8]) -> io::Result<Option<BlockRef>> {
+        for run in &self.spilled {
+            if let Some(block) = run.find(key)? {
+                return Ok(Some(block));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records block `block_index` of file `file_id` as the location of `hash`, which must not be
+    /// in the index yet
+    pub fn insert(&mut self, hash: H::Hash, file_id: u32, block_index: usize) {
+        let block_index = block_index.try_into().expect("too many blocks");
+        self.blocks.insert(
+            hash,
+            BlockRef {
+                file_id,
+                block_index,
+            },
+        );
+
+        // Blocks are moved in batches of at least half the limit, even if the file table alone
+        // takes most of it, to keep runs from getting tiny
+        if self.memory_limit.is_some_and(|memory_limit| {
+            self.memory_used() > memory_limit && self.blocks_memory() >= memory_limit / 2
+        }) {
+            if let Err(e) = self.spill() {
+                warn!("Could not move block index to disk, ignoring memory limit: {e}");
+                self.memory_limit = None;
+            }
+        }
+    }
+
+    /// Estimated memory used by blocks, including unused hash table capacity
+    fn blocks_memory(&self) -> usize {
+        self.blocks.capacity() * (mem::size_of::<(H::Hash, BlockRef)>() + 1)
+    }
+
+    /// Estimated memory used by blocks, files and the filters of spilled runs
+    fn memory_used(&self) -> usize {
+        self.blocks_memory()
+            + self.files_memory
+            + self
+                .spilled
+                .iter()
+                .map(|run| run.filter.memory_used())
+                .sum::<usize>()
+    }
+
+    /// Moves all blocks from memory to a new run on disk, and merges runs of similar size, so
+    /// each block is rewritten a logarithmic number of times. Blocks stay in memory if writing the
+    /// run fails.
+    fn spill(&mut self) -> io::Result<()> {
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        let mut records: Vec<Vec<u8>> = self
+            .blocks
+            .iter()
+            .map(|(hash, block)| {
+                let mut record = encode_hash(hash);
+                block.encode(&mut record);
+                record
+            })
+            .collect();
+        records.sort_unstable();
+
+        self.spilled.push(SpillRun::write(&records)?);
+        self.blocks = HashMap::new();
+
+        while let [.., first, second] = self.spilled.as_slice() {
+            if first.records > second.records {
+                break;
+            }
+            let merged = SpillRun::merge(first, second)?;
+            self.spilled.truncate(self.spilled.len() - 2);
+            self.spilled.push(merged);
+        }
+
+        info!(
+            "Moved block index to disk, {} blocks in {} runs",
+            self.spilled.iter().map(|run| run.records).sum::<u64>(),
+            self.spilled.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! Block-level deduplication for btrfs and other filesystems supporting `FIDEDUPERANGE`
+
+pub mod backend;
+pub mod config;
+pub mod db;
+pub mod dedup;
+mod deduplicator;
+pub mod dry_run;
+#[cfg(test)]
+mod fake_backend;
+pub mod fiemap;
+pub mod filter;
+pub mod hash;
+pub mod incremental;
+mod index;
+pub mod plan;
+pub mod report;
+pub mod scan;
+mod serde_path;
+pub mod stats;
+#[cfg(test)]
+mod tests;
+pub mod verify;
+mod workers;
+
+pub use dedup::{dedup, dedup_files, BlockDedupError, BlockLocation, FileVersion};
+pub use deduplicator::Deduplicator;
+pub use scan::{crawl_paths, scan_file, ScanOptions, ScanResult};
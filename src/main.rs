@@ -1,71 +1,549 @@
-use clap::Parser;
-use dedup::{BlockDedupError, BlockLocation};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc;
-use std::thread;
-use tracing::warn;
-
-mod dedup;
-mod scan;
+use clap::{ErrorKind, IntoApp, Parser, Subcommand};
+use fsdedup::config::{self, Config, FilterConfig, LimitsConfig};
+use fsdedup::db::{self, DbHeader, HashDb};
+use fsdedup::hash::{self, BlockHasher, HashAlgorithm};
+use fsdedup::plan::{PlanFormat, SourcePolicy};
+use fsdedup::report::{ReportFormat, RunReport};
+use fsdedup::scan::ChunkingMode;
+use fsdedup::stats::DbStats;
+use fsdedup::{verify, Deduplicator};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
+/// Without a subcommand, deduplicates the given roots like the dedup command
 #[derive(Parser, Debug)]
-struct Args {
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Config file. Command line options override its settings.
+    #[clap(long, global = true, value_name = "FILE", default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+
+    /// Print the settings merged from the config file and command line, and exit
+    #[clap(long, global = true)]
+    print_config: bool,
+
+    #[clap(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Scan and deduplicate the given roots
+    Dedup(Box<Args>),
+    /// Only hash files and save the hashes to the database, for a later dedup run
+    Scan(Box<ScanArgs>),
+    /// Scan and write the dedupe requests to a plan file, without modifying any files
+    Plan {
+        /// Plan file to write
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Plan file format
+        #[clap(long, arg_enum, default_value = "json-lines")]
+        format: PlanFormat,
+
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Make the dedupe requests of a plan file. Files modified since planning are skipped.
+    Apply {
+        /// Plan file written by the plan command
+        plan: PathBuf,
+
+        /// Number of threads making dedupe requests
+        #[clap(long)]
+        dedup_threads: Option<usize>,
+
+        /// Print a summary of the run when done
+        #[clap(long, arg_enum)]
+        report: Option<ReportFormat>,
+    },
+    /// Show duplicate statistics of the files in a hash database
+    Stats {
+        /// Hash database, the one of the config file by default
+        db: Option<PathBuf>,
+
+        #[clap(long, arg_enum, default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Maintain a hash database
+    Db {
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// Hash unchanged files of a hash database again and report files whose contents differ.
+    /// Exits with status 1 if any do.
+    Verify {
+        /// Hash database, the one of the config file by default
+        db: Option<PathBuf>,
+
+        #[clap(long, arg_enum, default_value = "text")]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Remove files deleted or modified since they were scanned
+    Gc {
+        /// Hash database, the one of the config file by default
+        db: Option<PathBuf>,
+    },
+    /// Remove files outside of the given roots
+    Compact {
+        /// Hash database, the one of the config file by default
+        db: Option<PathBuf>,
+
+        /// Root directories to keep, the ones of the config file by default. Can only be given
+        /// after the database.
+        root: Vec<PathBuf>,
+    },
+    /// Show the scan parameters and contents of a hash database
+    Inspect {
+        /// Hash database, the one of the config file by default
+        db: Option<PathBuf>,
+
+        /// List every file
+        #[clap(long)]
+        files: bool,
+    },
+}
+
+impl Command {
+    /// Hash database given to database commands
+    fn db(&self) -> Option<&PathBuf> {
+        match self {
+            Command::Stats { db, .. } | Command::Verify { db, .. } => db.as_ref(),
+            Command::Db { command } => match command {
+                DbCommand::Gc { db } => db.as_ref(),
+                DbCommand::Compact { db, .. } | DbCommand::Inspect { db, .. } => db.as_ref(),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Options of scanning and hashing
+#[derive(clap::Args, Debug)]
+struct ScanArgs {
     /// Root directory for optimization
     root: Vec<PathBuf>,
-    /// Deduplication block size
-    #[clap(short, default_value_t = 4096)]
-    block_size: usize,
+    /// Deduplication block size, 4096 by default
+    #[clap(short)]
+    block_size: Option<usize>,
+
+    /// Queue size for storing dedup tasks between scan and deduplication, 32 by default
+    #[clap(short)]
+    dedup_queue: Option<usize>,
+
+    /// Hash database, used to skip unchanged files between runs
+    #[clap(long)]
+    db: Option<PathBuf>,
 
-    /// Queue size for storing dedup tasks between scan and deduplication
-    #[clap(short, default_value_t = 32)]
-    dedup_queue: usize,
+    /// Only scan files changed since the previous run, using btrfs generation numbers.
+    /// Requires --db
+    #[clap(long, overrides_with = "no-incremental")]
+    incremental: bool,
+
+    /// Scan all files, even if the config file enables incremental runs
+    #[clap(long)]
+    no_incremental: bool,
+
+    /// Hash function for block and file contents, crc64 by default
+    #[clap(long, arg_enum)]
+    hash: Option<HashAlgorithm>,
+
+    /// How files are split into chunks for matching, fixed by default
+    #[clap(long, arg_enum)]
+    chunking: Option<ChunkingMode>,
+
+    /// Average size of content-defined chunks, 65536 by default
+    #[clap(long)]
+    cdc_avg_size: Option<usize>,
+
+    /// Only scan files matching this glob. Can be given multiple times.
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip files and directories matching this glob. Can be given multiple times.
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Read exclude globs from a file, one per line
+    #[clap(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Skip files and directories whose path relative to the root matches this regex
+    #[clap(long, value_name = "REGEX")]
+    exclude_regex: Vec<String>,
+
+    /// Honor gitignore-style files with this name in every directory, like .gitignore
+    #[clap(long, value_name = "NAME")]
+    ignore_file: Vec<String>,
+
+    /// Skip files smaller than this many bytes
+    #[clap(long, value_name = "BYTES")]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this many bytes
+    #[clap(long, value_name = "BYTES")]
+    max_size: Option<u64>,
+
+    /// Number of threads hashing files, all CPUs by default
+    #[clap(long)]
+    scan_threads: Option<usize>,
+
+    /// Print a summary of the run when done. Logs go to stderr.
+    #[clap(long, arg_enum)]
+    report: Option<ReportFormat>,
 }
 
-fn main() {
-    tracing_subscriber::fmt::init();
+/// Options of a scan and deduplication run
+#[derive(clap::Args, Debug)]
+struct Args {
+    #[clap(flatten)]
+    scan: ScanArgs,
+
+    /// Number of threads making dedupe requests, 1 by default. Each file is handled by a single
+    /// thread.
+    #[clap(long)]
+    dedup_threads: Option<usize>,
+
+    /// Deduplicate identical files as a whole, without matching individual blocks
+    #[clap(long, overrides_with = "no-whole-file")]
+    whole_file: bool,
+
+    /// Match individual blocks, even if the config file enables whole file mode
+    #[clap(long)]
+    no_whole_file: bool,
+
+    /// Only report what would be deduplicated, without modifying any files
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Memory for the block index in MiB, including its file table. Beyond that blocks are moved
+    /// to temporary files.
+    #[clap(long, value_name = "MIB")]
+    memory_limit: Option<usize>,
+
+    /// Scan all files first, then deduplicate each group of duplicate blocks against a chosen
+    /// source, instead of whichever copy was scanned first. Keeps all scan results in memory, so
+    /// it can't be combined with --memory-limit.
+    #[clap(long, overrides_with = "no-two-phase")]
+    two_phase: bool,
+
+    /// Deduplicate while scanning, even if the config file enables two-phase mode
+    #[clap(long)]
+    no_two_phase: bool,
+
+    /// How the source of each group of duplicate blocks is chosen, oldest by default. Requires
+    /// --two-phase
+    #[clap(long, arg_enum)]
+    source_policy: Option<SourcePolicy>,
+}
+
+/// Setting of a flag and its `--no-` negation, `None` if neither was given
+fn flag(enabled: bool, disabled: bool) -> Option<bool> {
+    match (enabled, disabled) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn exit_with(kind: ErrorKind, message: String) -> ! {
+    Cli::into_app().error(kind, message).exit()
+}
+
+impl ScanArgs {
+    /// Settings given on the command line
+    fn config(&self) -> Config {
+        Config {
+            roots: self.root.clone(),
+            block_size: self.block_size,
+            dedup_queue: self.dedup_queue,
+            db: self.db.clone(),
+            incremental: flag(self.incremental, self.no_incremental),
+            hash: self.hash,
+            chunking: self.chunking,
+            cdc_avg_size: self.cdc_avg_size,
+            filter: FilterConfig {
+                include: self.include.clone(),
+                exclude: self.exclude.clone(),
+                exclude_from: self.exclude_from.clone(),
+                exclude_regex: self.exclude_regex.clone(),
+                ignore_file: self.ignore_file.clone(),
+                min_size: self.min_size,
+                max_size: self.max_size,
+            },
+            limits: LimitsConfig {
+                scan_threads: self.scan_threads,
+                ..LimitsConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+}
+
+impl Args {
+    /// Settings given on the command line
+    fn config(&self) -> Config {
+        let config = self.scan.config();
+        Config {
+            whole_file: flag(self.whole_file, self.no_whole_file),
+            two_phase: flag(self.two_phase, self.no_two_phase),
+            source_policy: self.source_policy,
+            limits: LimitsConfig {
+                dedup_threads: self.dedup_threads,
+                memory_limit: self.memory_limit,
+                ..config.limits
+            },
+            ..config
+        }
+    }
+}
+
+impl Command {
+    /// Settings given on the command line
+    fn config(&self) -> Config {
+        match self {
+            Command::Dedup(args) | Command::Plan { args, .. } => args.config(),
+            Command::Scan(args) => args.config(),
+            Command::Apply { dedup_threads, .. } => Config {
+                limits: LimitsConfig {
+                    dedup_threads: *dedup_threads,
+                    ..LimitsConfig::default()
+                },
+                ..Config::default()
+            },
+            Command::Db {
+                command: DbCommand::Compact { db, root },
+            } => Config {
+                roots: root.clone(),
+                db: db.clone(),
+                ..Config::default()
+            },
+            Command::Stats { .. } | Command::Db { .. } | Command::Verify { .. } => Config {
+                db: self.db().cloned(),
+                ..Config::default()
+            },
+        }
+    }
+}
+
+/// Builds a run from settings merged with `Config::defaults`
+fn deduplicator(config: &Config) -> Deduplicator {
+    if config.incremental == Some(true) && config.db.is_none() {
+        exit_with(
+            ErrorKind::MissingRequiredArgument,
+            "Incremental runs need a hash database, set with --db".to_string(),
+        );
+    }
+    if config.source_policy.is_some() && config.two_phase != Some(true) {
+        exit_with(
+            ErrorKind::ArgumentConflict,
+            "A source policy is only used in two-phase mode, set with --two-phase".to_string(),
+        );
+    }
+    if config.limits.memory_limit.is_some() && config.two_phase == Some(true) {
+        exit_with(
+            ErrorKind::ArgumentConflict,
+            "Two-phase mode keeps all scan results in memory, it can't be used with --memory-limit"
+                .to_string(),
+        );
+    }
+
+    Deduplicator::new()
+        .config(config)
+        .unwrap_or_else(|e| exit_with(ErrorKind::InvalidValue, e.to_string()))
+}
+
+fn print_report(format: Option<ReportFormat>, report: &RunReport) {
+    match (format, &report.dry_run) {
+        (Some(format), _) => report.print(format),
+        (None, Some(recorder)) => recorder.print_report(),
+        (None, None) => {}
+    }
+}
 
-    let args = Args::parse();
+fn save_db<H: BlockHasher>(db: HashDb<H>, path: &Path) {
+    if let Err(e) = db.save(path) {
+        exit_with(
+            ErrorKind::Io,
+            format!("Could not save hash database {path:?}: {e:?}"),
+        )
+    }
+}
 
-    let mut block_locations: HashMap<_, BlockLocation> = HashMap::new();
+fn inspect<H: BlockHasher>(header: &DbHeader, db: &HashDb<H>, files: bool) {
+    println!("Version: {}", header.version);
+    println!("Hash: {:?}", header.hash);
+    println!("Scan options: {:?}", header.options);
+    println!("Files: {}", db.len());
+
+    let mut generations: Vec<_> = db.generations().iter().collect();
+    generations.sort();
+    for (root, generation) in generations {
+        println!("  generation {generation:>10}  {root:?}");
+    }
 
-    let (scanned_tx, scanned_rx) = mpsc::sync_channel(args.dedup_queue);
+    if files {
+        let mut results: Vec<_> = db.files().collect();
+        results.sort_by(|a, b| a.path.cmp(&b.path));
+        for result in results {
+            println!(
+                "{:>12}  {:>8}  {:?}",
+                result.size,
+                result.block_hashes.len(),
+                result.path
+            );
+        }
+    }
+}
 
-    // Crawlers in thread pool
-    let crawler_handle = thread::spawn(move || {
-        scan::crawl_paths(&args.root, args.block_size, scanned_tx);
+/// Runs a command reading a hash database, with the hash type it was written with
+fn db_command(command: Command, config: &Config) {
+    let path = config.db.clone().unwrap_or_else(|| {
+        exit_with(
+            ErrorKind::MissingRequiredArgument,
+            "No hash database given, and none set in the config file".to_string(),
+        )
     });
+    let header = db::read_header(&path).unwrap_or_else(|e| {
+        exit_with(
+            ErrorKind::Io,
+            format!("Could not read hash database {path:?}: {e:?}"),
+        )
+    });
+    match header.hash {
+        HashAlgorithm::Crc64 => db_command_with::<hash::Crc64>(&path, &header, command, config),
+        HashAlgorithm::Xxh3 => db_command_with::<hash::Xxh3>(&path, &header, command, config),
+        HashAlgorithm::Blake3 => db_command_with::<hash::Blake3>(&path, &header, command, config),
+        HashAlgorithm::Sha256 => db_command_with::<hash::Sha256>(&path, &header, command, config),
+    }
+}
 
-    // Main thread: dedup
-    while let Ok(scan_result) = scanned_rx.recv() {
-        for (number, block_hash) in scan_result.block_hashes.iter().enumerate() {
-            let block_location = scan_result.get_block_location(number);
-
-            match block_locations.get(block_hash) {
-                Some(x) => {
-                    let res = dedup::dedup(x.clone(), block_location);
-                    match res {
-                        Ok(_) => {}
-                        Err(BlockDedupError::SameBlock { block }) => {
-                            warn!("Block dedup struct points to exact same block: {block:?}");
-                        }
-                        Err(BlockDedupError::SameExtent { .. }) => {
-                            warn!("Possible hardlinks detected: {:?}", res.err());
-                        }
-                        Err(BlockDedupError::DedupInternal(e)) => {
-                            warn!("Dedup returned error: {e}");
-                        }
-                        Err(BlockDedupError::FileErrors(e1, e2)) => {
-                            warn!("I/O error: {e1:?}, {e2:?}");
-                        }
-                    }
-                }
-                None => {
-                    block_locations.insert(*block_hash, block_location);
+fn db_command_with<H: BlockHasher>(
+    path: &Path,
+    header: &DbHeader,
+    command: Command,
+    config: &Config,
+) {
+    let mut db = HashDb::<H>::open(path).unwrap_or_else(|e| {
+        exit_with(
+            ErrorKind::Io,
+            format!("Could not load hash database {path:?}: {e:?}"),
+        )
+    });
+
+    match command {
+        Command::Stats { format, .. } => DbStats::of(&db).print(format),
+        Command::Verify { format, .. } => {
+            let report = verify::verify(&db);
+            report.print(format);
+            if !report.mismatches.is_empty() {
+                process::exit(1);
+            }
+        }
+        Command::Db { command } => match command {
+            DbCommand::Gc { .. } => {
+                let removed = db.gc();
+                save_db(db, path);
+                println!("Removed {removed} files");
+            }
+            DbCommand::Compact { .. } => {
+                if config.roots.is_empty() {
+                    exit_with(
+                        ErrorKind::MissingRequiredArgument,
+                        "No roots to keep given, and none set in the config file".to_string(),
+                    );
                 }
-            };
+                let roots: Vec<PathBuf> = config
+                    .roots
+                    .iter()
+                    .map(|root| {
+                        fs::canonicalize(root).unwrap_or_else(|e| {
+                            exit_with(ErrorKind::Io, format!("Could not find {root:?}: {e}"))
+                        })
+                    })
+                    .collect();
+                let removed = db.retain_roots(&roots);
+                save_db(db, path);
+                println!("Removed {removed} files");
+            }
+            DbCommand::Inspect { files, .. } => inspect(header, &db, files),
+        },
+        _ => unreachable!("not a database command"),
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = Cli::parse();
+    // Only a config file given explicitly must exist
+    let file_config = if cli.config == Path::new(config::DEFAULT_CONFIG_PATH) {
+        Config::load_default()
+    } else {
+        Config::load(&cli.config)
+    }
+    .unwrap_or_else(|e| exit_with(ErrorKind::Io, e.to_string()));
+
+    let command = match cli.command {
+        Some(command) => command,
+        None => Command::Dedup(Box::new(cli.args)),
+    };
+    let config = Config::defaults()
+        .merge(file_config)
+        .merge(command.config());
+    if cli.print_config {
+        match config.to_toml() {
+            Ok(toml) => print!("{toml}"),
+            Err(e) => exit_with(ErrorKind::Io, format!("Could not print config: {e}")),
         }
+        return;
     }
 
-    let _ = crawler_handle.join();
+    match command {
+        Command::Dedup(args) => {
+            let report = deduplicator(&config).dry_run(args.dry_run).run();
+            print_report(args.scan.report, &report);
+        }
+        Command::Scan(args) => {
+            if config.db.is_none() {
+                exit_with(
+                    ErrorKind::MissingRequiredArgument,
+                    "The scan command needs --db to save the hashes to".to_string(),
+                );
+            }
+            let report = deduplicator(&config).scan();
+            print_report(args.report, &report);
+        }
+        Command::Plan {
+            output,
+            format,
+            args,
+        } => match deduplicator(&config)
+            .dry_run(args.dry_run)
+            .write_plan(&output, format)
+        {
+            Ok(report) => print_report(args.scan.report, &report),
+            Err(e) => exit_with(ErrorKind::Io, format!("Could not write {output:?}: {e}")),
+        },
+        Command::Apply { plan, report, .. } => match Deduplicator::new()
+            .dedup_threads(config.limits.dedup_threads.unwrap_or(1))
+            .apply_plan(&plan)
+        {
+            Ok(run_report) => print_report(report, &run_report),
+            Err(e) => exit_with(ErrorKind::Io, format!("Could not apply {plan:?}: {e}")),
+        },
+        command @ (Command::Stats { .. } | Command::Db { .. } | Command::Verify { .. }) => {
+            db_command(command, &config)
+        }
+    }
 }
@@ -0,0 +1,295 @@
+//! Deduplication planned over complete scan results, instead of against the first copy seen,
+//! and plan files separating the decision from the dedupe requests
+
+use crate::backend::DedupBackend;
+use crate::dedup::{BlockLocation, RangeMerger};
+use crate::fiemap::PhysicalRange;
+use crate::scan::ScanResult;
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::{fs, io};
+use tracing::{debug, warn};
+
+/// Start of binary plan files
+const PLAN_MAGIC: &[u8; 8] = b"FSDPLAN2";
+
+/// How the source of each group of duplicate blocks is picked
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourcePolicy {
+    /// Block of the file modified longest ago
+    Oldest,
+    /// Block whose extent is already shared by most copies, so the fewest extents are replaced.
+    /// Needs the physical location of every copy.
+    MostShared,
+}
+
+/// Block `.1` of file `.0`
+type BlockId = (usize, usize);
+
+/// Files kept open while choosing sources, limited to this many at once
+const MAX_OPEN_FILES: usize = 1024;
+
+/// Looks up physical ranges of blocks, opening each file once
+struct PhysicalRanges<'a, B> {
+    backend: &'a B,
+    /// Open file by file index, `None` if it couldn't be opened
+    open_files: HashMap<usize, Option<fs::File>>,
+}
+
+impl<'a, B: DedupBackend> PhysicalRanges<'a, B> {
+    fn new(backend: &'a B) -> Self {
+        Self {
+            backend,
+            open_files: HashMap::new(),
+        }
+    }
+
+    /// Physical ranges of `block`, or `None` if they can't be compared
+    fn get<H>(
+        &mut self,
+        files: &[ScanResult<H>],
+        (file, block): BlockId,
+    ) -> Option<Vec<PhysicalRange>> {
+        if self.open_files.len() >= MAX_OPEN_FILES && !self.open_files.contains_key(&file) {
+            self.open_files.clear();
+        }
+        let backend = self.backend;
+        let handle = self
+            .open_files
+            .entry(file)
+            .or_insert_with(|| backend.open(&files[file].path, false).ok())
+            .as_ref()?;
+
+        let location = files[file].get_block_location(block);
+        backend
+            .physical_ranges(handle, location.offset, location.length as u64)
+            .ok()
+            .flatten()
+    }
+}
+
+/// Returns the member of `group` files are deduplicated against
+fn choose_source<H, B: DedupBackend>(
+    files: &[ScanResult<H>],
+    group: &[BlockId],
+    policy: SourcePolicy,
+    physical_ranges: &mut PhysicalRanges<B>,
+) -> BlockId {
+    let oldest = |&(file, block): &BlockId| (files[file].mtime, &files[file].path, block);
+
+    match policy {
+        SourcePolicy::Oldest => *group.iter().min_by_key(|id| oldest(id)).unwrap(),
+        SourcePolicy::MostShared => {
+            let physical: Vec<Option<Vec<PhysicalRange>>> = group
+                .iter()
+                .map(|&id| physical_ranges.get(files, id))
+                .collect();
+            let mut copies: HashMap<&[PhysicalRange], usize> = HashMap::new();
+            for ranges in physical.iter().flatten() {
+                *copies.entry(ranges).or_default() += 1;
+            }
+            let shared = |index: usize| {
+                physical[index]
+                    .as_ref()
+                    .map_or(0, |ranges| copies[ranges.as_slice()])
+            };
+
+            // Oldest of the most shared
+            let index = (0..group.len())
+                .min_by_key(|&index| (usize::MAX - shared(index), oldest(&group[index])))
+                .unwrap();
+            group[index]
+        }
+    }
+}
+
+/// Plans deduplication of all duplicate blocks of `files`. Returns pairs of source and
+/// destination ranges, with consecutive blocks merged.
+pub fn plan_blocks<H: Eq + Hash, B: DedupBackend>(
+    files: &[ScanResult<H>],
+    policy: SourcePolicy,
+    backend: &B,
+) -> Vec<(BlockLocation, BlockLocation)> {
+    let mut groups: HashMap<&H, Vec<BlockId>> = HashMap::new();
+    for (file, scan_result) in files.iter().enumerate() {
+        for (block, hash) in scan_result.block_hashes.iter().enumerate() {
+            groups.entry(hash).or_default().push((file, block));
+        }
+    }
+
+    let mut physical_ranges = PhysicalRanges::new(backend);
+    let sources: HashMap<&H, BlockId> = groups
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(hash, group)| {
+            let source = choose_source(files, &group, policy, &mut physical_ranges);
+            (hash, source)
+        })
+        .collect();
+    debug!("Planned {} groups of duplicate blocks", sources.len());
+
+    // Destinations are visited in file order, so consecutive blocks can be merged
+    let mut plan = vec![];
+    for (file, scan_result) in files.iter().enumerate() {
+        let mut merger = RangeMerger::default();
+        for (block, hash) in scan_result.block_hashes.iter().enumerate() {
+            let (src_file, src_block) = match sources.get(hash) {
+                Some(&source) => source,
+                None => continue,
+            };
+            // Skip the source itself, and pairs of files deduplicated on previous runs
+            if (src_file, src_block) == (file, block)
+                || (files[src_file].deduplicated && scan_result.deduplicated)
+            {
+                continue;
+            }
+
+            let src = files[src_file].get_block_location(src_block);
+            plan.extend(merger.push(src, scan_result.get_block_location(block)));
+        }
+        plan.extend(merger.finish());
+    }
+    plan
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// One JSON object per line, easy to review and filter
+    JsonLines,
+    /// Compact bincode records
+    Binary,
+}
+
+/// Single planned dedupe request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlanOp {
+    pub src: BlockLocation,
+    pub dest: BlockLocation,
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Writes planned dedupe requests to a plan file
+pub struct PlanWriter {
+    writer: BufWriter<fs::File>,
+    format: PlanFormat,
+    ops: u64,
+    /// First write error, returned by `finish`
+    error: Option<io::Error>,
+}
+
+impl PlanWriter {
+    pub fn create(path: &Path, format: PlanFormat) -> io::Result<Self> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        if format == PlanFormat::Binary {
+            writer.write_all(PLAN_MAGIC)?;
+        }
+
+        Ok(Self {
+            writer,
+            format,
+            ops: 0,
+            error: None,
+        })
+    }
+
+    /// Appends a request deduplicating `dest` against `src`
+    pub fn write(&mut self, src: BlockLocation, dest: BlockLocation) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let op = PlanOp { src, dest };
+        let res = match self.format {
+            PlanFormat::JsonLines => serde_json::to_writer(&mut self.writer, &op)
+                .map_err(io::Error::from)
+                .and_then(|_| self.writer.write_all(b"\n")),
+            PlanFormat::Binary => {
+                bincode::serialize_into(&mut self.writer, &op).map_err(invalid_data)
+            }
+        };
+        match res {
+            Ok(()) => self.ops += 1,
+            Err(e) => {
+                warn!("Could not write plan file, skipping remaining requests: {e}");
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Number of requests written so far
+    pub fn ops(&self) -> u64 {
+        self.ops
+    }
+
+    /// Flushes the plan file. Returns the number of requests written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.writer.flush()?;
+        Ok(self.ops)
+    }
+}
+
+/// Reads requests from a plan file in either format
+pub struct PlanReader {
+    reader: BufReader<fs::File>,
+    format: PlanFormat,
+    line: String,
+}
+
+impl PlanReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let format = if reader.fill_buf()?.starts_with(PLAN_MAGIC) {
+            reader.consume(PLAN_MAGIC.len());
+            PlanFormat::Binary
+        } else {
+            PlanFormat::JsonLines
+        };
+
+        Ok(Self {
+            reader,
+            format,
+            line: String::new(),
+        })
+    }
+
+    fn read_op(&mut self) -> io::Result<Option<PlanOp>> {
+        match self.format {
+            PlanFormat::JsonLines => loop {
+                self.line.clear();
+                if self.reader.read_line(&mut self.line)? == 0 {
+                    return Ok(None);
+                }
+                if !self.line.trim().is_empty() {
+                    return Ok(Some(serde_json::from_str(&self.line)?));
+                }
+            },
+            PlanFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                bincode::deserialize_from(&mut self.reader)
+                    .map(Some)
+                    .map_err(invalid_data)
+            }
+        }
+    }
+}
+
+impl Iterator for PlanReader {
+    type Item = io::Result<PlanOp>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_op().transpose()
+    }
+}
@@ -0,0 +1,137 @@
+//! Summary of a run, for humans or for monitoring
+
+use crate::dedup::BlockDedupError;
+use crate::dry_run::DryRunRecorder;
+use clap::ArgEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(ArgEnum, Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Text,
+    /// Single JSON object on stdout
+    Json,
+}
+
+/// Elapsed time of each phase, in seconds
+#[derive(Serialize, Default, Debug)]
+pub struct PhaseTimes {
+    pub load_db: f64,
+    /// Crawling and hashing, overlaps with block deduplication
+    pub scan: f64,
+    /// Planning deduplication in two-phase mode
+    pub plan: f64,
+    /// Time spent in dedupe requests, summed over all dedup threads
+    pub dedup: f64,
+    pub save_db: f64,
+    pub total: f64,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct RunReport {
+    /// Files read and hashed
+    pub files_scanned: u64,
+    /// Unchanged files taken from the hash database
+    pub files_cached: u64,
+    /// Additional paths of files with multiple hard links, which were not read again
+    pub hard_links: u64,
+    pub bytes_hashed: u64,
+    /// Destination ranges or files submitted for deduplication
+    pub dedup_attempts: u64,
+    pub dedup_successes: u64,
+    /// Sum of `bytes_deduped` reported by the kernel
+    pub bytes_deduped: u64,
+    /// Number of failed attempts by `BlockDedupError` variant
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Bytes that would be reclaimed, in dry run mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run_bytes: Option<u64>,
+    /// Dedupe requests written to a plan file instead of being made
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub planned: Option<u64>,
+    /// Candidates recorded in dry run mode
+    #[serde(skip)]
+    pub dry_run: Option<DryRunRecorder>,
+    /// Destinations of failed dedup attempts, which are not marked as deduplicated
+    #[serde(skip)]
+    pub(crate) failed_dests: HashSet<PathBuf>,
+    pub elapsed: PhaseTimes,
+}
+
+impl RunReport {
+    /// Counts the result of a single dedup attempt
+    pub fn record(&mut self, res: &Result<u64, BlockDedupError>) {
+        self.dedup_attempts += 1;
+        match res {
+            Ok(bytes) => {
+                self.dedup_successes += 1;
+                self.bytes_deduped += bytes;
+            }
+            Err(e) => {
+                if let BlockDedupError::Differs { bytes_deduped, .. } = e {
+                    self.bytes_deduped += bytes_deduped;
+                }
+                *self.errors.entry(e.kind()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Adds dedup results of `other`, which was collected by another thread
+    pub fn merge(&mut self, other: RunReport) {
+        self.dedup_attempts += other.dedup_attempts;
+        self.dedup_successes += other.dedup_successes;
+        self.bytes_deduped += other.bytes_deduped;
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_default() += count;
+        }
+        self.failed_dests.extend(other.failed_dests);
+        self.elapsed.dedup += other.elapsed.dedup;
+    }
+
+    /// Measures `f` and adds its duration to `phase`
+    pub fn time<T>(phase: &mut f64, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        *phase += start.elapsed().as_secs_f64();
+        result
+    }
+
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Json => println!("{}", serde_json::to_string(self).unwrap()),
+            ReportFormat::Text => {
+                println!(
+                    "Files scanned: {}, unchanged: {}, hard links: {}",
+                    self.files_scanned, self.files_cached, self.hard_links
+                );
+                println!("Bytes hashed: {}", self.bytes_hashed);
+                println!(
+                    "Dedup attempts: {}, succeeded: {}",
+                    self.dedup_attempts, self.dedup_successes
+                );
+                println!("Bytes deduplicated: {}", self.bytes_deduped);
+                for (kind, count) in &self.errors {
+                    println!("  {kind}: {count}");
+                }
+                if let Some(bytes) = self.dry_run_bytes {
+                    println!("Bytes that would be reclaimed: {bytes}");
+                }
+                if let Some(planned) = self.planned {
+                    println!("Dedupe requests planned: {planned}");
+                }
+                let elapsed = &self.elapsed;
+                println!(
+                    "Elapsed: {:.2}s (load db {:.2}s, scan {:.2}s, plan {:.2}s, dedup {:.2}s, save db {:.2}s)",
+                    elapsed.total,
+                    elapsed.load_db,
+                    elapsed.scan,
+                    elapsed.plan,
+                    elapsed.dedup,
+                    elapsed.save_db
+                );
+            }
+        }
+    }
+}
@@ -1,43 +1,172 @@
-use crate::dedup::BlockLocation;
-use crc64fast::Digest;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use crate::backend;
+use crate::db::HashDb;
+use crate::dedup::{self, BlockLocation, FileVersion, MAX_DEDUP_LENGTH};
+use crate::filter::FileFilter;
+use crate::hash::BlockHasher;
+use crate::incremental;
+use clap::ArgEnum;
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::io::{BufReader, Error, Read};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{mpsc, Mutex};
 use std::time::SystemTime;
-use std::{fs, io};
-use tracing::info;
+use std::{cmp, fs, io, mem};
+use tracing::{debug, info, warn};
 use walkdir::WalkDir;
 
-type Hash = u64;
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChunkingMode {
+    /// Fixed-size blocks
+    Fixed,
+    /// Content-defined chunks, finding duplicates shifted by whole blocks
+    Cdc,
+}
+
+/// How files are split into chunks for matching
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Every block is a chunk
+    Fixed,
+    /// Chunk boundaries are placed between blocks depending on block contents, so that chunks
+    /// stay aligned to blocks
+    ContentDefined {
+        min_blocks: usize,
+        avg_blocks: usize,
+        max_blocks: usize,
+    },
+}
+
+impl Chunking {
+    /// Content-defined chunking with `avg_size` bytes chunks on average. Chunks are no longer
+    /// than `MAX_DEDUP_LENGTH`, so each can be deduplicated with a single request.
+    pub fn content_defined(block_size: usize, avg_size: usize) -> Self {
+        let limit_blocks = (MAX_DEDUP_LENGTH / block_size).max(1);
+        let avg_blocks = (avg_size / block_size).clamp(1, limit_blocks);
+        Self::ContentDefined {
+            min_blocks: (avg_blocks / 4).max(1),
+            avg_blocks,
+            max_blocks: (avg_blocks * 4).min(limit_blocks),
+        }
+    }
 
-#[derive(Clone)]
-pub struct ScanResult {
+    /// Returns true if a chunk of `blocks` blocks ends with block `data`
+    fn is_boundary(&self, blocks: usize, data: &[u8]) -> bool {
+        match *self {
+            Chunking::Fixed => true,
+            Chunking::ContentDefined {
+                min_blocks,
+                avg_blocks,
+                max_blocks,
+            } => {
+                let divisor = avg_blocks.saturating_sub(min_blocks).max(1) as u64;
+                blocks >= max_blocks
+                    || (blocks >= min_blocks
+                        && xxhash_rust::xxh3::xxh3_64(data).is_multiple_of(divisor))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub block_size: usize,
+    pub chunking: Chunking,
+}
+
+/// Scan result with block hashes of type `H`, produced by one of the `BlockHasher`s
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScanResult<H> {
+    #[serde(with = "crate::serde_path")]
     pub path: PathBuf,
     block_size: usize,
-    pub block_hashes: Vec<Hash>,
-    pub last_block_size: usize,
+    /// Hashes of fixed blocks, or of content-defined chunks
+    pub block_hashes: Vec<H>,
+    /// Start offsets of content-defined chunks
+    pub chunk_offsets: Option<Vec<u64>>,
+    /// Hash of the whole file contents
+    pub file_hash: H,
     pub mtime: SystemTime,
+    pub dev: u64,
     pub ino: u64,
+    /// Inode generation, see `dedup::inode_generation`
+    pub generation: u64,
+    pub size: u64,
+    /// Other paths of the same file, if it has multiple hard links
+    #[serde(with = "crate::serde_path::list")]
+    pub links: Vec<PathBuf>,
+    /// Every dedupe request with this file as destination succeeded on a previous run, so pairs
+    /// of deduplicated files are not tried again
+    pub deduplicated: bool,
+    /// Result was taken from the hash database instead of reading the file
+    #[serde(skip)]
+    pub cached: bool,
 }
 
-impl ScanResult {
+impl<H: Clone> ScanResult<H> {
+    /// Copy without block hashes and links, which still locates blocks
+    pub fn without_hashes(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            block_size: self.block_size,
+            block_hashes: vec![],
+            chunk_offsets: self.chunk_offsets.clone(),
+            file_hash: self.file_hash.clone(),
+            mtime: self.mtime,
+            dev: self.dev,
+            ino: self.ino,
+            generation: self.generation,
+            size: self.size,
+            links: vec![],
+            deduplicated: self.deduplicated,
+            cached: self.cached,
+        }
+    }
+}
+
+impl<H> ScanResult<H> {
+    /// Version of the file at the time of the scan
+    pub fn version(&self) -> FileVersion {
+        FileVersion {
+            dev: self.dev,
+            ino: self.ino,
+            mtime: self.mtime,
+            size: self.size,
+            generation: self.generation,
+        }
+    }
+
+    /// Location of block or chunk `index`. Only the last block of a file may be shorter than
+    /// the block size.
     pub fn get_block_location(&self, index: usize) -> BlockLocation {
+        let (offset, end) = match &self.chunk_offsets {
+            Some(chunk_offsets) => (
+                chunk_offsets[index],
+                chunk_offsets.get(index + 1).copied().unwrap_or(self.size),
+            ),
+            None => {
+                let offset = (self.block_size as u64) * (index as u64);
+                (offset, cmp::min(offset + self.block_size as u64, self.size))
+            }
+        };
+
         BlockLocation {
             path: self.path.clone(),
-            offset: (self.block_size as u64) * (index as u64),
-            length: if index == self.block_hashes.len() - 1 {
-                self.last_block_size
-            } else {
-                self.block_size
-            },
+            offset,
+            length: (end - offset) as usize,
+            version: self.version(),
         }
     }
 }
 
+#[derive(Debug)]
 pub enum ScanError {
     IoError(io::Error),
+    /// Size or modification time changed while the file was read
+    Modified,
 }
 
 impl From<io::Error> for ScanError {
@@ -46,63 +175,316 @@ impl From<io::Error> for ScanError {
     }
 }
 
+/// Reads until `buf` is full or the end of file is reached, so blocks stay aligned regardless of
+/// short reads. Returns the number of bytes read.
+fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 #[tracing::instrument]
-pub fn scan_file(path: &Path, block_size: usize) -> Result<ScanResult, ScanError> {
+pub fn scan_file<H: BlockHasher>(
+    path: &Path,
+    options: &ScanOptions,
+) -> Result<ScanResult<H::Hash>, ScanError> {
     let absolute_path = fs::canonicalize(path)?;
 
     info!("Scanning {}", absolute_path.to_string_lossy());
 
-    let file = fs::File::open(absolute_path.clone())?;
+    let file = fs::File::open(&absolute_path)?;
+    scan_reader::<H>(absolute_path, &file, BufReader::new(&file), options)
+}
+
+/// Hashes contents of `file` read through `reader`. Fails with `ScanError::Modified` if the file
+/// doesn't stay the same while it is read.
+pub(crate) fn scan_reader<H: BlockHasher>(
+    path: PathBuf,
+    file: &fs::File,
+    mut reader: impl Read,
+    options: &ScanOptions,
+) -> Result<ScanResult<H::Hash>, ScanError> {
+    let block_size = options.block_size;
     let metadata = file.metadata()?;
     let file_size = metadata.len();
-    let blocks_total = ((file_size + (block_size as u64) - 1) / (block_size as u64)) as usize;
+    let blocks_total = file_size.div_ceil(block_size as u64) as usize;
 
-    let mut result = ScanResult {
-        path: absolute_path,
-        block_size,
-        block_hashes: Vec::with_capacity(blocks_total),
-        last_block_size: (file_size % (block_size as u64)) as usize,
-        mtime: metadata.modified()?,
-        ino: metadata.ino(),
-    };
+    let mut block_hashes = Vec::with_capacity(blocks_total);
+    let mut chunk_offsets = vec![];
+    let mut file_hasher = H::default();
+    let mut chunk_hasher = H::default();
+    let mut chunk_blocks = 0;
+    let mut offset = 0;
 
-    let mut reader = BufReader::new(file);
     let mut buf = vec![0u8; block_size];
     loop {
-        match reader.read(&mut buf)? {
+        match read_block(&mut reader, &mut buf)? {
             0 => break,
             chunk_size => {
                 let chunk_data = &buf[0..chunk_size];
 
-                let chunk_hash = {
-                    let mut digest = Digest::new();
-                    digest.write(chunk_data);
-                    digest.sum64()
-                };
+                if chunk_blocks == 0 {
+                    chunk_offsets.push(offset);
+                }
+                chunk_hasher.update(chunk_data);
+                chunk_blocks += 1;
+                offset += chunk_size as u64;
+
+                if options.chunking.is_boundary(chunk_blocks, chunk_data) {
+                    block_hashes.push(mem::take(&mut chunk_hasher).finish());
+                    chunk_blocks = 0;
+                }
+                file_hasher.update(chunk_data);
+            }
+        }
+    }
+    if chunk_blocks > 0 {
+        block_hashes.push(chunk_hasher.finish());
+    }
+
+    let mtime = metadata.modified()?;
+    let metadata_after = file.metadata()?;
+    if offset != file_size
+        || metadata_after.len() != file_size
+        || metadata_after.modified()? != mtime
+    {
+        return Err(ScanError::Modified);
+    }
+
+    Ok(ScanResult {
+        path,
+        block_size,
+        block_hashes,
+        chunk_offsets: match options.chunking {
+            Chunking::Fixed => None,
+            Chunking::ContentDefined { .. } => Some(chunk_offsets),
+        },
+        file_hash: file_hasher.finish(),
+        mtime,
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        generation: dedup::inode_generation(file)?,
+        size: file_size,
+        links: vec![],
+        deduplicated: false,
+        cached: false,
+    })
+}
+
+/// Looks up an unchanged file in `db`, so it doesn't have to be read again
+fn lookup_cached<H: BlockHasher>(db: &HashDb<H>, path: &Path) -> Option<ScanResult<H::Hash>> {
+    let absolute_path = fs::canonicalize(path).ok()?;
+    let metadata = fs::symlink_metadata(&absolute_path).ok()?;
+
+    db.lookup(&absolute_path, &metadata).map(|result| {
+        debug!("Unchanged {}", absolute_path.to_string_lossy());
+        ScanResult {
+            cached: true,
+            ..result.clone()
+        }
+    })
+}
+
+/// Returns true if files of `size` bytes are skipped, because of `filter` size limits or because
+/// they may be stored inline in metadata, when not larger than `max_inline`
+fn is_skipped_size(filter: &FileFilter, max_inline: Option<u64>, size: u64) -> bool {
+    !filter.accepts_size(size) || max_inline.is_some_and(|max_inline| size <= max_inline)
+}
+
+/// Lists files under `root` changed since the generation recorded in `db`. Unchanged files are
+/// sent to `scanned_tx` straight from the database. Returns `None` if a full walk is needed.
+fn changed_files<H: BlockHasher>(
+    root: &Path,
+    filter: &FileFilter,
+    max_inline: Option<u64>,
+    db: &HashDb<H>,
+    scanned_tx: &mpsc::SyncSender<ScanResult<H::Hash>>,
+) -> Option<Vec<PathBuf>> {
+    let root = fs::canonicalize(root).ok()?;
+    let generation = db.generation(&root)?;
+
+    let mut changed: Vec<PathBuf> = match incremental::find_new(&root, generation) {
+        Ok(changed) => changed
+            .into_iter()
+            .filter(|path| !filter.is_excluded(&root, path, false))
+            .collect(),
+        Err(e) => {
+            warn!("Could not list changed files in {root:?}, falling back to full scan: {e}");
+            return None;
+        }
+    };
+    info!(
+        "{} files changed in {root:?} since generation {generation}",
+        changed.len()
+    );
+
+    let unlisted = send_unchanged(&root, filter, max_inline, db, &changed, scanned_tx);
+    changed.extend(unlisted);
+    Some(changed)
+}
 
-                result.block_hashes.push(chunk_hash);
+/// Sends files of `db` under `root`, which are not in `changed`, to `scanned_tx` if they are still
+/// unchanged. Deleted files are dropped. Returns files modified without being listed in `changed`,
+/// which have to be scanned again.
+pub(crate) fn send_unchanged<H: BlockHasher>(
+    root: &Path,
+    filter: &FileFilter,
+    max_inline: Option<u64>,
+    db: &HashDb<H>,
+    changed: &[PathBuf],
+    scanned_tx: &mpsc::SyncSender<ScanResult<H::Hash>>,
+) -> Vec<PathBuf> {
+    let changed: HashSet<&Path> = changed.iter().map(PathBuf::as_path).collect();
+    let mut modified = vec![];
+    for result in db.files_under(root) {
+        if changed.contains(result.path.as_path())
+            || filter.is_excluded(root, &result.path, false)
+            || is_skipped_size(filter, max_inline, result.size)
+        {
+            continue;
+        }
+
+        let metadata = match fs::symlink_metadata(&result.path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        match db.lookup(&result.path, &metadata) {
+            Some(result) => {
+                let cached = ScanResult {
+                    cached: true,
+                    ..result.clone()
+                };
+                scanned_tx.send(cached).unwrap();
             }
+            None => modified.push(result.path.clone()),
         }
     }
-    Ok(result)
+    modified
+}
+
+/// Lists all regular files under `root`, which are not excluded by `filter`
+fn walk_files<'a>(root: &'a Path, filter: &'a FileFilter) -> impl Iterator<Item = PathBuf> + 'a {
+    WalkDir::new(root)
+        .same_file_system(true)
+        .into_iter()
+        .filter_entry(move |e| !filter.is_excluded(root, e.path(), e.file_type().is_dir()))
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+}
+
+/// Scans the file at `path`, unless it's unchanged in `db`, and sends the result along with
+/// `links` to the same file
+fn scan_path<H: BlockHasher>(
+    path: &Path,
+    links: Vec<PathBuf>,
+    options: &ScanOptions,
+    db: &HashDb<H>,
+    scanned_tx: &mpsc::SyncSender<ScanResult<H::Hash>>,
+) {
+    let scan_result = match lookup_cached(db, path) {
+        Some(cached) => Ok(cached),
+        None => scan_file::<H>(path, options),
+    };
+
+    match scan_result {
+        Ok(scan_result) => scanned_tx
+            .send(ScanResult {
+                links,
+                ..scan_result
+            })
+            .unwrap(),
+        Err(ScanError::IoError(e)) => warn!("Could not scan {path:?}: {e}"),
+        Err(ScanError::Modified) => warn!("Skipping {path:?}, it was modified while scanning"),
+    }
 }
 
-pub fn crawl_paths(paths: &[PathBuf], block_size: usize, scanned_tx: mpsc::SyncSender<ScanResult>) {
+/// Scans all files under `paths` accepted by `filter`. Files which may be stored inline can't be
+/// deduplicated and are skipped as well. In `incremental` mode only files changed since the last
+/// run, according to btrfs generation numbers, are looked at.
+///
+/// Files with multiple hard links are scanned once, after the walk, with all paths seen.
+pub fn crawl_paths<H: BlockHasher>(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    options: &ScanOptions,
+    db: &HashDb<H>,
+    incremental: bool,
+    scanned_tx: mpsc::SyncSender<ScanResult<H::Hash>>,
+) {
+    // Paths of each (dev, ino) with multiple links
+    let hardlinked: Mutex<HashMap<(u64, u64), Vec<PathBuf>>> = Mutex::default();
+
     paths
         .iter()
-        .flat_map(|path| {
-            WalkDir::new(path)
-                .same_file_system(true)
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|e| e.file_type().is_file())
+        .flat_map(|root| {
+            let max_inline = backend::max_inline_size(root).unwrap_or_else(|e| {
+                warn!("Could not get max inline size of {root:?}: {e}");
+                None
+            });
+
+            let files: Box<dyn Iterator<Item = PathBuf> + Send> = match incremental
+                .then(|| changed_files(root, filter, max_inline, db, &scanned_tx))
+                .flatten()
+            {
+                Some(changed) => Box::new(changed.into_iter()),
+                None => Box::new(walk_files(root, filter)),
+            };
+            files.map(move |path| (path, max_inline))
         })
         .par_bridge()
-        .for_each(|entry| {
-            let scan_result = scan_file(entry.path(), block_size);
+        .for_each(|(path, max_inline)| {
+            // Checked before the file is opened
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("Could not scan {path:?}: {e}");
+                    return;
+                }
+            };
+            if is_skipped_size(filter, max_inline, metadata.len()) {
+                debug!("Skipping {path:?} by size");
+                return;
+            }
 
-            if let Ok(scan_result) = scan_result {
-                scanned_tx.send(scan_result).unwrap();
+            if metadata.nlink() > 1 {
+                hardlinked
+                    .lock()
+                    .unwrap()
+                    .entry((metadata.dev(), metadata.ino()))
+                    .or_default()
+                    .push(path);
+                return;
             }
+
+            scan_path(&path, vec![], options, db, &scanned_tx);
+        });
+
+    hardlinked
+        .into_inner()
+        .unwrap()
+        .into_par_iter()
+        .for_each(|(_, paths)| {
+            // Sorted, so the same path is scanned and looked up in the database every run
+            let mut paths: Vec<PathBuf> = paths
+                .iter()
+                .filter_map(|path| fs::canonicalize(path).ok())
+                .collect();
+            paths.sort();
+            paths.dedup();
+            if paths.is_empty() {
+                return;
+            }
+
+            let links = paths.split_off(1);
+            debug!("Hard links of {:?}: {links:?}", paths[0]);
+            scan_path(&paths[0], links, options, db, &scanned_tx);
         });
 }
@@ -0,0 +1,150 @@
+//! Serializes paths as raw bytes, so file names which aren't valid UTF-8 can be stored
+//!
+//! Human-readable formats get a string instead, with backslashes and bytes which aren't valid
+//! UTF-8 escaped as `\\` and `\xHH`.
+//!
+//! Use with `#[serde(with = "crate::serde_path")]`, or the `list` and `map` submodules for
+//! collections of paths.
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    let bytes = path.as_os_str().as_bytes();
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&escape(bytes))
+    } else {
+        serializer.serialize_bytes(bytes)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    if deserializer.is_human_readable() {
+        let escaped = String::deserialize(deserializer)?;
+        let bytes = unescape(&escaped).map_err(de::Error::custom)?;
+        Ok(OsString::from_vec(bytes).into())
+    } else {
+        deserializer.deserialize_byte_buf(PathVisitor)
+    }
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(position) = rest.find('\\') {
+        bytes.extend(&rest.as_bytes()[..position]);
+        rest = &rest[position + 1..];
+        if let Some(after) = rest.strip_prefix('\\') {
+            bytes.push(b'\\');
+            rest = after;
+        } else {
+            let byte = rest
+                .strip_prefix('x')
+                .and_then(|hex| hex.get(..2))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid escape in path {escaped:?}"))?;
+            bytes.push(byte);
+            rest = &rest[3..];
+        }
+    }
+    bytes.extend(rest.as_bytes());
+    Ok(bytes)
+}
+
+struct PathVisitor;
+
+impl<'de> Visitor<'de> for PathVisitor {
+    type Value = PathBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("path bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<PathBuf, E> {
+        Ok(OsStr::from_bytes(v).into())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<PathBuf, E> {
+        Ok(OsString::from_vec(v).into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(OsString::from_vec(bytes).into())
+    }
+}
+
+/// Path serialized with this module, as an element of a collection
+struct PathBytes<'a>(&'a Path);
+
+impl Serialize for PathBytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct PathBytesBuf(PathBuf);
+
+impl<'de> Deserialize<'de> for PathBytesBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(PathBytesBuf)
+    }
+}
+
+/// `Vec<PathBuf>`
+pub mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| PathBytes(path)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        let paths = Vec::<PathBytesBuf>::deserialize(deserializer)?;
+        Ok(paths.into_iter().map(|PathBytesBuf(path)| path).collect())
+    }
+}
+
+/// `HashMap<PathBuf, V>`
+pub mod map {
+    use super::*;
+
+    pub fn serialize<S: Serializer, V: Serialize>(
+        map: &HashMap<PathBuf, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(path, value)| (PathBytes(path), value)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<PathBuf, V>, D::Error> {
+        let map = HashMap::<PathBytesBuf, V>::deserialize(deserializer)?;
+        Ok(map
+            .into_iter()
+            .map(|(PathBytesBuf(path), value)| (path, value))
+            .collect())
+    }
+}
@@ -0,0 +1,69 @@
+//! Duplicate statistics of a hash database
+
+use crate::db::HashDb;
+use crate::dry_run::format_bytes;
+use crate::hash::BlockHasher;
+use crate::report::ReportFormat;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Duplicates among the files of a hash database, whether or not they are already shared
+#[derive(Serialize, Default, Debug)]
+pub struct DbStats {
+    pub files: u64,
+    pub bytes: u64,
+    /// Blocks or content-defined chunks
+    pub blocks: u64,
+    pub unique_blocks: u64,
+    /// Bytes of blocks identical to another block
+    pub duplicate_bytes: u64,
+    /// Files identical to another file, not counting the first copy
+    pub duplicate_files: u64,
+    pub duplicate_file_bytes: u64,
+}
+
+impl DbStats {
+    pub fn of<H: BlockHasher>(db: &HashDb<H>) -> Self {
+        let mut stats = Self::default();
+        let mut blocks = HashSet::new();
+        let mut files = HashSet::new();
+
+        for result in db.files() {
+            stats.files += 1;
+            stats.bytes += result.size;
+            if result.size > 0 && !files.insert((result.size, result.file_hash)) {
+                stats.duplicate_files += 1;
+                stats.duplicate_file_bytes += result.size;
+            }
+
+            for (index, hash) in result.block_hashes.iter().enumerate() {
+                stats.blocks += 1;
+                if !blocks.insert(hash) {
+                    stats.duplicate_bytes += result.get_block_location(index).length as u64;
+                }
+            }
+        }
+        stats.unique_blocks = blocks.len() as u64;
+        stats
+    }
+
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Json => println!("{}", serde_json::to_string(self).unwrap()),
+            ReportFormat::Text => {
+                println!("Files: {} ({})", self.files, format_bytes(self.bytes));
+                println!("Blocks: {}, unique: {}", self.blocks, self.unique_blocks);
+                println!(
+                    "Duplicate blocks: {} ({})",
+                    self.duplicate_bytes,
+                    format_bytes(self.duplicate_bytes)
+                );
+                println!(
+                    "Duplicate files: {} ({})",
+                    self.duplicate_files,
+                    format_bytes(self.duplicate_file_bytes)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,924 @@
+//! Scan, match and dedup pipeline tests on the fake backend
+
+use crate::backend::{DedupBackend, DedupeDest, DedupeStatus};
+use crate::config::{Config, FilterConfig};
+use crate::db::HashDb;
+use crate::fake_backend::FakeBackend;
+use crate::filter::FileFilter;
+use crate::hash::{Crc64, HashAlgorithm};
+use crate::index::BlockIndex;
+use crate::plan::{self, PlanFormat, PlanOp, PlanReader, SourcePolicy};
+use crate::scan::{self, Chunking, ChunkingMode, ScanError, ScanOptions, ScanResult};
+use crate::stats::DbStats;
+use crate::{dedup, verify, BlockDedupError, Deduplicator};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+const BLOCK_SIZE: usize = 4096;
+/// Length of the partial block at the end of test files
+const TAIL: usize = 100;
+
+/// Block filled with `seed`, so blocks with different seeds differ
+fn block(seed: u8) -> Vec<u8> {
+    vec![seed; BLOCK_SIZE]
+}
+
+/// Writes a file of blocks filled with `seeds`, followed by a partial block of 0xff
+fn write_blocks(dir: &TempDir, name: &str, seeds: &[u8]) -> PathBuf {
+    let path = dir.path().join(name);
+    let mut data: Vec<u8> = seeds.iter().flat_map(|&seed| block(seed)).collect();
+    data.extend([0xff; TAIL]);
+    fs::write(&path, data).unwrap();
+    path
+}
+
+/// Offset of block `index`
+fn offset(index: usize) -> u64 {
+    (index * BLOCK_SIZE) as u64
+}
+
+const OPTIONS: ScanOptions = ScanOptions {
+    block_size: BLOCK_SIZE,
+    chunking: Chunking::Fixed,
+};
+
+fn scan(path: &Path) -> Result<ScanResult<u64>, ScanError> {
+    scan::scan_file::<Crc64>(path, &OPTIONS)
+}
+
+/// Returns at most 1000 bytes per read
+struct ShortReader<R>(R);
+
+impl<R: Read> Read for ShortReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(1000);
+        self.0.read(&mut buf[..length])
+    }
+}
+
+/// Changes the length of the file at `path` to `new_size` after the first read
+struct ResizingReader<'a, R> {
+    reader: R,
+    path: &'a Path,
+    new_size: Option<u64>,
+}
+
+impl<R: Read> Read for ResizingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        if let Some(new_size) = self.new_size.take() {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(self.path)?
+                .set_len(new_size)?;
+        }
+        Ok(read)
+    }
+}
+
+/// Scans the file at `path`, resizing it to `new_size` while it is read
+fn scan_resized(path: &Path, new_size: u64) -> Result<ScanResult<u64>, ScanError> {
+    let file = fs::File::open(path).unwrap();
+    let reader = ResizingReader {
+        reader: &file,
+        path,
+        new_size: Some(new_size),
+    };
+    scan::scan_reader::<Crc64>(path.to_path_buf(), &file, reader, &OPTIONS)
+}
+
+/// Sets the modification time of `path` to `secs` seconds after the epoch
+fn set_mtime(path: &Path, secs: u64) {
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap();
+}
+
+fn deduplicator(dir: &TempDir, backend: &FakeBackend) -> Deduplicator<FakeBackend> {
+    Deduplicator::new()
+        .root(dir.path())
+        .block_size(BLOCK_SIZE)
+        .backend(backend.clone())
+}
+
+#[test]
+fn identical_files_are_deduplicated() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2, 3, 4]);
+    let b = write_blocks(&dir, "b", &[1, 2, 3, 4]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).run();
+
+    let size = offset(4) + TAIL as u64;
+    assert!(backend.is_shared_path(&a, 0, &b, 0, size));
+    assert_eq!(report.bytes_deduped, size);
+    // Consecutive blocks are merged into a single request
+    assert_eq!(backend.requests(), 1);
+}
+
+#[test]
+fn requests_with_many_destinations_are_split() {
+    let dir = TempDir::new().unwrap();
+    let src = fs::File::open(write_blocks(&dir, "a", &[1])).unwrap();
+    let dest = fs::File::open(write_blocks(&dir, "b", &[1])).unwrap();
+    let mut dests: Vec<DedupeDest> = (0..dedup::MAX_DEDUP_DESTINATIONS * 2)
+        .map(|_| DedupeDest::new(&dest, 0))
+        .collect();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let length = BLOCK_SIZE as u64;
+    backend.dedupe_all(&src, 0, length, &mut dests).unwrap();
+
+    assert_eq!(
+        backend.request_sizes(),
+        vec![dedup::MAX_DEDUP_DESTINATIONS; 2]
+    );
+    assert!(dests
+        .iter()
+        .all(|dest| dest.status == DedupeStatus::Same && dest.bytes_deduped == length));
+}
+
+#[test]
+fn failing_destination_does_not_fail_batch() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let c = write_blocks(&dir, "c", &[1, 2]);
+    let d = write_blocks(&dir, "d", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let size = offset(2) + TAIL as u64;
+    // a is the oldest, so it's the source and the others share a request
+    set_mtime(&a, 0);
+    backend.fail_dedupe(&c);
+
+    let report = deduplicator(&dir, &backend).run();
+
+    assert_eq!(backend.requests(), 1);
+    assert_eq!(report.dedup_successes, 2);
+    assert_eq!(report.bytes_deduped, 2 * size);
+    assert_eq!(report.errors.get("file_errors"), Some(&1));
+    assert!(backend.is_shared_path(&a, 0, &b, 0, size));
+    assert!(!backend.is_shared_path(&a, 0, &c, 0, size));
+    assert!(backend.is_shared_path(&a, 0, &d, 0, size));
+}
+
+#[test]
+fn shifted_blocks_are_deduplicated() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2, 3]);
+    let b = write_blocks(&dir, "b", &[4, 1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).run();
+
+    assert!(backend.is_shared_path(&a, 0, &b, offset(1), offset(2)));
+    assert!(backend.is_shared_path(&a, offset(3), &b, offset(3), TAIL as u64));
+    assert!(!backend.is_shared_path(&a, offset(2), &b, 0, offset(1)));
+    assert_eq!(report.bytes_deduped, offset(2) + TAIL as u64);
+}
+
+#[test]
+fn whole_files_are_deduplicated() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let c = write_blocks(&dir, "c", &[1, 2]);
+    write_blocks(&dir, "d", &[3]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).whole_file(true).run();
+
+    let size = offset(2) + TAIL as u64;
+    assert!(backend.is_shared_path(&a, 0, &b, 0, size));
+    assert!(backend.is_shared_path(&a, 0, &c, 0, size));
+    assert_eq!(report.dedup_successes, 2);
+    assert_eq!(report.bytes_deduped, 2 * size);
+}
+
+#[test]
+fn dry_run_makes_no_requests() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).dry_run(true).run();
+
+    assert_eq!(backend.requests(), 0);
+    assert_eq!(report.dedup_attempts, 0);
+    assert!(!backend.is_shared_path(&a, 0, &b, 0, offset(2)));
+}
+
+#[test]
+fn dry_run_skips_shared_ranges() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    write_blocks(&dir, "b", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    deduplicator(&dir, &backend).run();
+
+    let report = deduplicator(&dir, &backend).dry_run(true).run();
+    assert_eq!(report.dry_run_bytes, Some(0));
+    let report = deduplicator(&dir, &backend)
+        .dry_run(true)
+        .whole_file(true)
+        .run();
+    assert_eq!(report.dry_run_bytes, Some(0));
+}
+
+#[test]
+fn source_ranges_are_looked_up_once_per_request() {
+    let dir = TempDir::new().unwrap();
+    for name in ["a", "b", "c", "d"] {
+        write_blocks(&dir, name, &[1, 2]);
+    }
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    deduplicator(&dir, &backend).run();
+
+    // Source and each of the 3 destinations of the single request
+    assert_eq!(backend.requests(), 1);
+    assert_eq!(backend.physical_queries(), 4);
+}
+
+#[test]
+fn shared_ranges_are_skipped_on_next_run() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    write_blocks(&dir, "b", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    deduplicator(&dir, &backend).run();
+    let report = deduplicator(&dir, &backend).run();
+
+    assert_eq!(backend.requests(), 1);
+    assert_eq!(report.dedup_successes, 0);
+    assert_eq!(report.errors.get("already_shared"), Some(&1));
+}
+
+#[test]
+fn excluded_files_are_not_deduplicated() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b.sqlite-wal", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let filter = FileFilter::new(&[], &["*.sqlite-wal".to_string()], &[], &[]).unwrap();
+
+    let report = deduplicator(&dir, &backend).filter(filter).run();
+
+    assert_eq!(report.files_scanned, 1);
+    assert_eq!(backend.requests(), 0);
+    assert!(!backend.is_shared_path(&a, 0, &b, 0, offset(2)));
+}
+
+#[test]
+fn files_outside_size_limits_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    write_blocks(&dir, "b", &[1, 2]);
+    write_blocks(&dir, "c", &[1, 2, 3, 4]);
+    write_blocks(&dir, "d", &[1, 2, 3, 4]);
+    write_blocks(&dir, "e", &[]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let filter = FileFilter::default().size_limits(offset(1), Some(offset(3)));
+
+    let report = deduplicator(&dir, &backend).filter(filter).run();
+
+    assert_eq!(report.files_scanned, 2);
+    assert_eq!(report.bytes_deduped, offset(2) + TAIL as u64);
+}
+
+#[test]
+fn hard_links_are_scanned_once() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    fs::hard_link(&a, dir.path().join("b")).unwrap();
+    let c = write_blocks(&dir, "c", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).run();
+
+    let size = offset(2) + TAIL as u64;
+    assert_eq!(report.files_scanned, 2);
+    assert_eq!(report.hard_links, 1);
+    assert!(report.errors.is_empty());
+    assert!(backend.is_shared_path(&a, 0, &c, 0, size));
+}
+
+#[test]
+fn empty_file_has_no_blocks() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("empty");
+    fs::write(&path, []).unwrap();
+
+    let result = scan(&path).unwrap();
+
+    assert_eq!(result.size, 0);
+    assert!(result.block_hashes.is_empty());
+}
+
+#[test]
+fn aligned_file_ends_with_full_block() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("aligned");
+    fs::write(&path, [block(1), block(2)].concat()).unwrap();
+
+    let result = scan(&path).unwrap();
+
+    assert_eq!(result.block_hashes.len(), 2);
+    let last = result.get_block_location(1);
+    assert_eq!((last.offset, last.length), (offset(1), BLOCK_SIZE));
+}
+
+#[test]
+fn aligned_files_are_deduplicated() {
+    let dir = TempDir::new().unwrap();
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    fs::write(&a, [block(1), block(2)].concat()).unwrap();
+    fs::write(&b, [block(1), block(2)].concat()).unwrap();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).run();
+
+    assert!(backend.is_shared_path(&a, 0, &b, 0, offset(2)));
+    assert_eq!(report.bytes_deduped, offset(2));
+}
+
+#[test]
+fn short_reads_keep_blocks_aligned() {
+    let dir = TempDir::new().unwrap();
+    let path = write_blocks(&dir, "a", &[1, 2, 3]);
+    let file = fs::File::open(&path).unwrap();
+
+    let result =
+        scan::scan_reader::<Crc64>(path.clone(), &file, ShortReader(&file), &OPTIONS).unwrap();
+
+    assert_eq!(result.block_hashes, scan(&path).unwrap().block_hashes);
+    let last = result.get_block_location(3);
+    assert_eq!((last.offset, last.length), (offset(3), TAIL));
+}
+
+#[test]
+fn file_growing_during_scan_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let path = write_blocks(&dir, "a", &[1, 2]);
+
+    let result = scan_resized(&path, offset(4));
+
+    assert!(matches!(result, Err(ScanError::Modified)));
+}
+
+#[test]
+fn file_shrinking_during_scan_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let path = write_blocks(&dir, "a", &[1, 2, 3, 4]);
+
+    let result = scan_resized(&path, offset(1));
+
+    assert!(matches!(result, Err(ScanError::Modified)));
+}
+
+#[test]
+fn files_modified_after_scan_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let src = scan(&a).unwrap().get_block_location(0);
+    let dest = scan(&b).unwrap().get_block_location(0);
+
+    // Contents are still the same, only the version tells the file was rewritten
+    set_mtime(&b, 0);
+    let results = dedup::dedup(&backend, src, vec![dest]).unwrap();
+
+    assert!(matches!(results[0], Err(BlockDedupError::Stale { .. })));
+    assert_eq!(backend.requests(), 0);
+}
+
+#[test]
+fn content_defined_chunks_fit_in_one_request() {
+    match Chunking::content_defined(BLOCK_SIZE, 1 << 30) {
+        Chunking::ContentDefined {
+            min_blocks,
+            avg_blocks,
+            max_blocks,
+        } => {
+            assert!(min_blocks <= avg_blocks && avg_blocks <= max_blocks);
+            assert!(max_blocks * BLOCK_SIZE <= dedup::MAX_DEDUP_LENGTH);
+        }
+        Chunking::Fixed => unreachable!(),
+    }
+}
+
+#[test]
+fn content_defined_chunks_are_within_limits() {
+    let dir = TempDir::new().unwrap();
+    let seeds: Vec<u8> = (0..200).collect();
+    let path = write_blocks(&dir, "a", &seeds);
+    let chunking = Chunking::content_defined(BLOCK_SIZE, 4 * BLOCK_SIZE);
+    let options = ScanOptions {
+        block_size: BLOCK_SIZE,
+        chunking,
+    };
+
+    let result = scan::scan_file::<Crc64>(&path, &options).unwrap();
+
+    let (min_blocks, max_blocks) = match chunking {
+        Chunking::ContentDefined {
+            min_blocks,
+            max_blocks,
+            ..
+        } => (min_blocks, max_blocks),
+        Chunking::Fixed => unreachable!(),
+    };
+    let offsets = result.chunk_offsets.unwrap();
+    assert_eq!(offsets[0], 0);
+    assert_eq!(offsets.len(), result.block_hashes.len());
+    // Some chunks end at content-defined boundaries rather than at the maximum length
+    assert!(offsets.len() > seeds.len() / max_blocks + 1);
+    for chunk in offsets.windows(2) {
+        let length = (chunk[1] - chunk[0]) as usize;
+        assert_eq!(length % BLOCK_SIZE, 0);
+        assert!((min_blocks..=max_blocks).contains(&(length / BLOCK_SIZE)));
+    }
+}
+
+#[test]
+fn content_defined_chunks_match_shifted_content() {
+    let dir = TempDir::new().unwrap();
+    let seeds: Vec<u8> = (0..100).collect();
+    let shifted: Vec<u8> = [200, 201, 202].iter().chain(&seeds).copied().collect();
+    let a = write_blocks(&dir, "a", &seeds);
+    let b = write_blocks(&dir, "b", &shifted);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend)
+        .chunking(ChunkingMode::Cdc)
+        .cdc_avg_size(4 * BLOCK_SIZE)
+        .run();
+
+    // Chunks line up again after the first boundary following the inserted blocks
+    assert!(report.bytes_deduped >= offset(seeds.len() / 2));
+    assert!(backend.is_shared_path(&a, offset(50), &b, offset(53), offset(50)));
+}
+
+#[test]
+fn blocks_are_found_in_spilled_index() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2, 3]);
+    let b = write_blocks(&dir, "b", &[4, 1, 2]);
+    let c = write_blocks(&dir, "c", &[3, 5, 6]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    // Every block is moved to disk right after it's added
+    let report = deduplicator(&dir, &backend).memory_limit(Some(1)).run();
+
+    assert!(backend.is_shared_path(&a, 0, &b, offset(1), offset(2)));
+    assert!(backend.is_shared_path(&a, offset(2), &c, 0, offset(1)));
+    assert_eq!(report.bytes_deduped, offset(3) + 2 * TAIL as u64);
+}
+
+#[test]
+fn spilled_runs_are_merged_and_searched() {
+    const BLOCKS: usize = 1000;
+    let dir = TempDir::new().unwrap();
+    let mut file = scan(&write_blocks(&dir, "a", &[1])).unwrap();
+    file.size = offset(BLOCKS);
+    // Spills after every block, so runs are merged all the time
+    let mut index = BlockIndex::<Crc64>::new(Some(1));
+    let file_id = index.add_file(&file);
+
+    for block in 0..BLOCKS {
+        index.insert(block as u64 * 7919, file_id, block);
+    }
+
+    for block in 0..BLOCKS {
+        let (location, _) = index.get(&(block as u64 * 7919)).unwrap();
+        assert_eq!(location.offset, offset(block));
+    }
+    assert!(index.get(&1).is_none());
+}
+
+#[test]
+fn dedup_threads_deduplicate_all_files() {
+    let dir = TempDir::new().unwrap();
+    // Every file is the destination of two requests, with different sets of files
+    let paths: Vec<PathBuf> = (0..8)
+        .map(|i| write_blocks(&dir, &format!("{i}"), &[20 + i / 4, 10 + i, 1]))
+        .collect();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).dedup_threads(4).run();
+
+    let tail = offset(1) + TAIL as u64;
+    for (i, path) in paths.iter().enumerate().skip(1) {
+        assert!(backend.is_shared_path(&paths[0], offset(2), path, offset(2), tail));
+        let first = &paths[i / 4 * 4];
+        assert!(backend.is_shared_path(first, 0, path, 0, offset(1)));
+    }
+    // Requests into the same file are never made by two workers at once
+    let dest_threads: Vec<usize> = paths
+        .iter()
+        .map(|path| backend.dest_threads(path))
+        .collect();
+    assert!(dest_threads.iter().all(|&threads| threads <= 1));
+    assert_eq!(report.dedup_successes, 13);
+    assert_eq!(report.bytes_deduped, 7 * tail + 6 * offset(1));
+}
+
+#[test]
+fn two_phase_deduplicates_all_copies() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2, 3]);
+    let b = write_blocks(&dir, "b", &[4, 1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let report = deduplicator(&dir, &backend).two_phase(true).run();
+
+    assert!(backend.is_shared_path(&a, 0, &b, offset(1), offset(2)));
+    assert_eq!(report.bytes_deduped, offset(2) + TAIL as u64);
+}
+
+#[test]
+fn oldest_file_is_planned_as_source() {
+    let dir = TempDir::new().unwrap();
+    let paths = ["a", "b", "c"].map(|name| write_blocks(&dir, name, &[1, 2]));
+    for (path, mtime) in paths.iter().zip([2000, 1000, 3000]) {
+        set_mtime(path, mtime);
+    }
+    let files: Vec<_> = paths.iter().map(|path| scan(path).unwrap()).collect();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    let plan = plan::plan_blocks(&files, SourcePolicy::Oldest, &backend);
+
+    // One merged range for each other file
+    assert_eq!(plan.len(), 2);
+    assert!(plan.iter().all(|(src, _)| src.path == files[1].path));
+}
+
+#[test]
+fn most_shared_extent_is_planned_as_source() {
+    let dir = TempDir::new().unwrap();
+    let paths = ["a", "b", "c"].map(|name| write_blocks(&dir, name, &[1, 2]));
+    for (path, mtime) in paths.iter().zip([1000, 2000, 3000]) {
+        set_mtime(path, mtime);
+    }
+    let files: Vec<_> = paths.iter().map(|path| scan(path).unwrap()).collect();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let dests = [(files[2].path.as_path(), files[2].version())];
+    dedup::dedup_files(&backend, &files[1].path, &files[1].version(), &dests).unwrap();
+
+    let plan = plan::plan_blocks(&files, SourcePolicy::MostShared, &backend);
+
+    assert_eq!(plan.len(), 2);
+    assert!(plan.iter().all(|(src, _)| src.path == files[1].path));
+}
+
+#[test]
+fn written_plan_is_applied_later() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2, 3]);
+    let b = write_blocks(&dir, "b", &[4, 1, 2]);
+    let c = write_blocks(&dir, "c", &[1, 2, 3]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    for format in [PlanFormat::JsonLines, PlanFormat::Binary] {
+        let plan_dir = TempDir::new().unwrap();
+        let plan_path = plan_dir.path().join("plan");
+        let requests = backend.requests();
+        let report = deduplicator(&dir, &backend)
+            .two_phase(true)
+            .write_plan(&plan_path, format)
+            .unwrap();
+        assert_eq!(backend.requests(), requests);
+        // Blocks and tail of b are not consecutive in a
+        assert_eq!(report.planned, Some(3));
+
+        let report = deduplicator(&dir, &backend).apply_plan(&plan_path).unwrap();
+        assert_eq!(report.dedup_attempts, 3);
+    }
+
+    assert!(backend.is_shared_path(&a, 0, &b, offset(1), offset(2)));
+    assert!(backend.is_shared_path(&a, 0, &c, 0, offset(3) + TAIL as u64));
+}
+
+#[test]
+fn plan_keeps_paths_which_are_not_utf8() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a\\b", &[1, 2]);
+    let b = dir.path().join(OsStr::from_bytes(b"caf\xe9"));
+    fs::copy(&a, &b).unwrap();
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let a = fs::canonicalize(a).unwrap();
+    let b = fs::canonicalize(b).unwrap();
+
+    for format in [PlanFormat::JsonLines, PlanFormat::Binary] {
+        let plan_dir = TempDir::new().unwrap();
+        let plan_path = plan_dir.path().join("plan");
+        deduplicator(&dir, &backend)
+            .write_plan(&plan_path, format)
+            .unwrap();
+
+        let ops: Vec<PlanOp> = PlanReader::open(&plan_path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(ops.len(), 1);
+        let mut paths = [ops[0].src.path.clone(), ops[0].dest.path.clone()];
+        paths.sort();
+        assert_eq!(paths, [a.clone(), b.clone()]);
+    }
+}
+
+#[test]
+fn files_modified_after_planning_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let plan_dir = TempDir::new().unwrap();
+    let plan_path = plan_dir.path().join("plan");
+    deduplicator(&dir, &backend)
+        .whole_file(true)
+        .write_plan(&plan_path, PlanFormat::JsonLines)
+        .unwrap();
+
+    set_mtime(&b, 0);
+    let report = deduplicator(&dir, &backend).apply_plan(&plan_path).unwrap();
+
+    assert_eq!(report.errors.get("stale"), Some(&1));
+    assert_eq!(backend.requests(), 0);
+}
+
+#[test]
+fn scan_saves_hashes_without_deduplicating() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    write_blocks(&dir, "b", &[1, 2]);
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    deduplicator(&dir, &backend)
+        .db(Some(db_path.clone()))
+        .scan();
+
+    assert_eq!(backend.requests(), 0);
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    let stats = DbStats::of(&db);
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.duplicate_files, 1);
+    assert_eq!(stats.duplicate_bytes, offset(2) + TAIL as u64);
+}
+
+#[test]
+fn scanned_files_are_deduplicated_later() {
+    for whole_file in [false, true] {
+        let dir = TempDir::new().unwrap();
+        let a = write_blocks(&dir, "a", &[1, 2]);
+        let b = write_blocks(&dir, "b", &[1, 2]);
+        let db_dir = TempDir::new().unwrap();
+        let db_path = db_dir.path().join("db");
+        let backend = FakeBackend::new(BLOCK_SIZE);
+        let deduplicator = deduplicator(&dir, &backend)
+            .db(Some(db_path))
+            .whole_file(whole_file);
+        deduplicator.scan();
+
+        let report = deduplicator.run();
+
+        assert_eq!(report.files_cached, 2);
+        assert_eq!(backend.requests(), 1);
+        assert!(backend.is_shared_path(&a, 0, &b, 0, offset(2) + TAIL as u64));
+
+        // Skipped once both were deduplicated
+        let report = deduplicator.run();
+        assert_eq!(report.dedup_attempts, 0);
+    }
+}
+
+#[test]
+fn files_under_other_roots_are_kept_in_db() {
+    let dir1 = TempDir::new().unwrap();
+    let dir2 = TempDir::new().unwrap();
+    write_blocks(&dir1, "a", &[1, 2]);
+    write_blocks(&dir1, "b", &[1, 2]);
+    write_blocks(&dir2, "c", &[3, 4]);
+    write_blocks(&dir2, "d", &[3, 4]);
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    deduplicator(&dir1, &backend)
+        .db(Some(db_path.clone()))
+        .scan();
+    deduplicator(&dir2, &backend)
+        .db(Some(db_path.clone()))
+        .scan();
+
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    assert_eq!(db.len(), 4);
+
+    // Deduplicating one root leaves the files of the other one as they were
+    deduplicator(&dir1, &backend)
+        .db(Some(db_path.clone()))
+        .run();
+
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    assert_eq!(db.len(), 4);
+    let dir2_path = fs::canonicalize(dir2.path()).unwrap();
+    let deduplicated: Vec<bool> = db
+        .files()
+        .filter(|result| result.path.starts_with(&dir2_path))
+        .map(|result| result.deduplicated)
+        .collect();
+    assert_eq!(deduplicated, vec![false, false]);
+}
+
+#[test]
+fn failed_dedup_is_retried_on_next_run() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    set_mtime(&a, 1000);
+    set_mtime(&b, 1000);
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let deduplicator = deduplicator(&dir, &backend).db(Some(db_path.clone()));
+    deduplicator.scan();
+
+    // Changed in place without updating the database, so the dedupe request fails
+    write_blocks(&dir, "b", &[1, 3]);
+    set_mtime(&b, 1000);
+    let report = deduplicator.run();
+    assert_eq!(report.errors.get("differs"), Some(&1));
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    assert_eq!(db.files().filter(|result| result.deduplicated).count(), 1);
+
+    write_blocks(&dir, "b", &[1, 2]);
+    set_mtime(&b, 1000);
+    let report = deduplicator.run();
+    assert_eq!(report.dedup_successes, 1);
+    assert!(backend.is_shared_path(&a, 0, &b, 0, offset(2) + TAIL as u64));
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    assert!(db.files().all(|result| result.deduplicated));
+}
+
+#[test]
+fn non_utf8_paths_are_saved_in_db() {
+    let dir = TempDir::new().unwrap();
+    let name = OsStr::from_bytes(b"caf\xe9");
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = dir.path().join(name);
+    fs::copy(&a, &b).unwrap();
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+
+    deduplicator(&dir, &backend).db(Some(db_path.clone())).run();
+
+    let db = HashDb::<Crc64>::open(&db_path).unwrap();
+    let b = fs::canonicalize(b).unwrap();
+    assert!(db.files().any(|result| result.path == b));
+}
+
+#[test]
+fn unchanged_files_are_sent_from_db() {
+    let dir = TempDir::new().unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    let a = write_blocks(&dir, "a", &[1]);
+    let b = write_blocks(&dir, "b", &[2]);
+    let c = write_blocks(&dir, "c", &[3]);
+    let d = write_blocks(&dir, "d", &[4]);
+    set_mtime(&c, 1000);
+    let mut db = HashDb::<Crc64>::new(OPTIONS);
+    for path in [&a, &b, &c, &d] {
+        db.insert(scan(&root.join(path.file_name().unwrap())).unwrap());
+    }
+    let filter = FileFilter::new(&[], &[], &[], &[]).unwrap();
+
+    fs::remove_file(&b).unwrap();
+    set_mtime(&c, 2000);
+    let (scanned_tx, scanned_rx) = mpsc::sync_channel(4);
+    let modified = scan::send_unchanged(&root, &filter, None, &db, &[root.join("d")], &scanned_tx);
+    drop(scanned_tx);
+
+    // Deleted files are dropped, modified ones scanned again, listed ones left to the caller
+    assert_eq!(modified, vec![root.join("c")]);
+    let sent: Vec<ScanResult<u64>> = scanned_rx.into_iter().collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].path, root.join("a"));
+    assert!(sent[0].cached);
+}
+
+#[test]
+fn gc_removes_deleted_files() {
+    let dir = TempDir::new().unwrap();
+    write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b", &[1, 2]);
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    deduplicator(&dir, &backend)
+        .db(Some(db_path.clone()))
+        .scan();
+
+    fs::remove_file(b).unwrap();
+    let mut db = HashDb::<Crc64>::open(&db_path).unwrap();
+
+    assert_eq!(db.gc(), 1);
+    assert_eq!(db.len(), 1);
+}
+
+#[test]
+fn verify_reports_contents_changed_in_place() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    write_blocks(&dir, "b", &[1, 2]);
+    set_mtime(&a, 1000);
+    let db_dir = TempDir::new().unwrap();
+    let db_path = db_dir.path().join("db");
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    deduplicator(&dir, &backend)
+        .db(Some(db_path.clone()))
+        .scan();
+
+    // Same size and mtime, so the database still takes the file as unchanged
+    let mut data = fs::read(&a).unwrap();
+    data[0] ^= 1;
+    fs::write(&a, data).unwrap();
+    set_mtime(&a, 1000);
+    let report = verify::verify(&HashDb::<Crc64>::open(&db_path).unwrap());
+
+    assert_eq!(report.files_verified, 2);
+    assert_eq!(report.mismatches, vec![fs::canonicalize(&a).unwrap()]);
+}
+
+#[test]
+fn command_line_overrides_config_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("fsdedup.toml");
+    fs::write(
+        &path,
+        "roots = [\"/srv\"]\nblock-size = 8192\nhash = \"xxh3\"\nwhole-file = true\n\n[filter]\nexclude = [\"*.tmp\"]\n",
+    )
+    .unwrap();
+    let command_line = Config {
+        block_size: Some(16384),
+        // --no-whole-file
+        whole_file: Some(false),
+        filter: FilterConfig {
+            exclude: vec!["*.log".to_string()],
+            ..FilterConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let config = Config::defaults()
+        .merge(Config::load(&path).unwrap())
+        .merge(command_line);
+
+    assert_eq!(config.roots, vec![PathBuf::from("/srv")]);
+    assert_eq!(config.block_size, Some(16384));
+    assert_eq!(config.hash, Some(HashAlgorithm::Xxh3));
+    assert_eq!(config.whole_file, Some(false));
+    assert_eq!(config.dedup_queue, Config::defaults().dedup_queue);
+    assert_eq!(config.filter.exclude, vec!["*.log".to_string()]);
+    // Printed settings read back the same
+    assert_eq!(
+        toml::from_str::<Config>(&config.to_toml().unwrap()).unwrap(),
+        config
+    );
+}
+
+#[test]
+fn config_filter_is_applied() {
+    let dir = TempDir::new().unwrap();
+    let a = write_blocks(&dir, "a", &[1, 2]);
+    let b = write_blocks(&dir, "b.tmp", &[1, 2]);
+    let backend = FakeBackend::new(BLOCK_SIZE);
+    let config = Config {
+        filter: FilterConfig {
+            exclude: vec!["*.tmp".to_string()],
+            ..FilterConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let report = deduplicator(&dir, &backend).config(&config).unwrap().run();
+
+    assert_eq!(report.files_scanned, 1);
+    assert!(!backend.is_shared_path(&a, 0, &b, 0, offset(2)));
+}
@@ -0,0 +1,97 @@
+//! Checking that files still have the contents recorded in a hash database
+
+use crate::db::HashDb;
+use crate::hash::BlockHasher;
+use crate::report::ReportFormat;
+use crate::scan::{self, ScanError};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use tracing::warn;
+
+#[derive(Serialize, Default, Debug)]
+pub struct VerifyReport {
+    /// Files read and compared with the database
+    pub files_verified: u64,
+    /// Files deleted or modified since they were scanned, which were not compared
+    pub files_changed: u64,
+    /// Files that could not be read
+    pub errors: u64,
+    /// Files with different contents, although they were not modified since they were scanned
+    pub mismatches: Vec<PathBuf>,
+}
+
+enum Outcome {
+    Verified,
+    Changed,
+    Mismatch(PathBuf),
+    Error,
+}
+
+/// Hashes all unchanged files of `db` again and compares them with the recorded hashes
+pub fn verify<H: BlockHasher>(db: &HashDb<H>) -> VerifyReport {
+    let options = db.options();
+    let files: Vec<_> = db.files().collect();
+
+    let outcomes: Vec<Outcome> = files
+        .into_par_iter()
+        .map(|expected| {
+            match fs::symlink_metadata(&expected.path) {
+                Ok(metadata) if db.lookup(&expected.path, &metadata).is_some() => {}
+                _ => return Outcome::Changed,
+            }
+
+            match scan::scan_file::<H>(&expected.path, &options) {
+                Ok(actual)
+                    if actual.file_hash == expected.file_hash
+                        && actual.block_hashes == expected.block_hashes =>
+                {
+                    Outcome::Verified
+                }
+                Ok(_) => {
+                    warn!("Contents of {:?} differ from the database", expected.path);
+                    Outcome::Mismatch(expected.path.clone())
+                }
+                Err(ScanError::Modified) => Outcome::Changed,
+                Err(ScanError::IoError(e)) => {
+                    warn!("Could not read {:?}: {e}", expected.path);
+                    Outcome::Error
+                }
+            }
+        })
+        .collect();
+
+    let mut report = VerifyReport::default();
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Verified => report.files_verified += 1,
+            Outcome::Changed => report.files_changed += 1,
+            Outcome::Mismatch(path) => {
+                report.files_verified += 1;
+                report.mismatches.push(path);
+            }
+            Outcome::Error => report.errors += 1,
+        }
+    }
+    report.mismatches.sort();
+    report
+}
+
+impl VerifyReport {
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Json => println!("{}", serde_json::to_string(self).unwrap()),
+            ReportFormat::Text => {
+                println!(
+                    "Files verified: {}, changed since scanned: {}, unreadable: {}",
+                    self.files_verified, self.files_changed, self.errors
+                );
+                println!("Mismatches: {}", self.mismatches.len());
+                for path in &self.mismatches {
+                    println!("  {path:?}");
+                }
+            }
+        }
+    }
+}
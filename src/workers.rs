@@ -0,0 +1,201 @@
+//! Threads submitting dedupe requests while scanning and matching go on
+
+use crate::backend::DedupBackend;
+use crate::dedup::{self, BlockDedupError, BlockLocation, FileVersion};
+use crate::report::RunReport;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use tracing::{debug, warn};
+
+/// Jobs waiting for each worker
+const WORKER_QUEUE: usize = 64;
+
+fn log_dedup_result(res: Result<u64, BlockDedupError>) {
+    match res {
+        Ok(_) => {}
+        Err(BlockDedupError::SameBlock { block }) => {
+            warn!("Block dedup struct points to exact same block: {block:?}");
+        }
+        Err(BlockDedupError::SameExtent { .. }) => {
+            warn!("Same range reached twice: {:?}", res.err());
+        }
+        Err(BlockDedupError::AlreadyShared { path1, path2 }) => {
+            debug!("Already deduplicated: {path1:?}, {path2:?}");
+        }
+        Err(BlockDedupError::Differs {
+            path1,
+            path2,
+            requested,
+            bytes_deduped,
+        }) => {
+            warn!(
+                "Contents differ, {bytes_deduped} of {requested} bytes deduplicated: {path1:?}, {path2:?}"
+            );
+        }
+        Err(BlockDedupError::Stale { path }) => {
+            warn!("Skipping {path:?}, it was modified since it was scanned");
+        }
+        Err(BlockDedupError::DedupInternal(e)) => {
+            warn!("Dedup returned error: {e}");
+        }
+        Err(BlockDedupError::FileErrors(e1, e2)) => {
+            warn!("I/O error: {e1:?}, {e2:?}");
+        }
+    }
+}
+
+/// Whether the destination of `res` may still hold a copy of the source, so it has to be tried
+/// again on the next run
+fn is_failure(res: &Result<u64, BlockDedupError>) -> bool {
+    !matches!(
+        res,
+        Ok(_)
+            | Err(BlockDedupError::SameBlock { .. })
+            | Err(BlockDedupError::SameExtent { .. })
+            | Err(BlockDedupError::AlreadyShared { .. })
+    )
+}
+
+enum DedupJob {
+    Ranges {
+        src: BlockLocation,
+        dests: Vec<BlockLocation>,
+    },
+    Files {
+        src: PathBuf,
+        src_version: FileVersion,
+        dests: Vec<(PathBuf, FileVersion)>,
+    },
+}
+
+impl DedupJob {
+    fn run<B: DedupBackend>(self, backend: &B, report: &mut RunReport) {
+        let dest_paths: Vec<PathBuf> = match &self {
+            DedupJob::Ranges { dests, .. } => dests.iter().map(|dest| dest.path.clone()).collect(),
+            DedupJob::Files { dests, .. } => dests.iter().map(|(path, _)| path.clone()).collect(),
+        };
+        let results = RunReport::time(&mut report.elapsed.dedup, || match self {
+            DedupJob::Ranges { src, dests } => dedup::dedup(backend, src, dests),
+            DedupJob::Files {
+                src,
+                src_version,
+                dests,
+            } => {
+                let dests: Vec<(&Path, FileVersion)> = dests
+                    .iter()
+                    .map(|(path, version)| (path.as_path(), *version))
+                    .collect();
+                dedup::dedup_files(backend, &src, &src_version, &dests)
+            }
+        });
+
+        match results {
+            Ok(results) => {
+                for (res, path) in results.into_iter().zip(dest_paths) {
+                    if is_failure(&res) {
+                        report.failed_dests.insert(path);
+                    }
+                    report.record(&res);
+                    log_dedup_result(res);
+                }
+            }
+            Err(e) => {
+                report.failed_dests.extend(dest_paths);
+                let res = Err(e);
+                report.record(&res);
+                log_dedup_result(res);
+            }
+        }
+    }
+}
+
+/// Pool of threads making dedupe requests. Destinations are assigned to workers by their inode,
+/// so all ranges of a file are deduplicated by a single worker, in order, and the same inode is
+/// never locked by two requests at once.
+pub struct DedupWorkers<'scope> {
+    senders: Vec<mpsc::SyncSender<DedupJob>>,
+    handles: Vec<thread::ScopedJoinHandle<'scope, RunReport>>,
+}
+
+impl<'scope> DedupWorkers<'scope> {
+    pub fn new<'env, B: DedupBackend>(
+        scope: &'scope thread::Scope<'scope, 'env>,
+        backend: &'env B,
+        threads: usize,
+    ) -> Self {
+        let (senders, handles) = (0..threads.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<DedupJob>(WORKER_QUEUE);
+                let handle = scope.spawn(move || {
+                    let mut report = RunReport::default();
+                    for job in receiver {
+                        job.run(backend, &mut report);
+                    }
+                    report
+                });
+                (sender, handle)
+            })
+            .unzip();
+
+        Self { senders, handles }
+    }
+
+    /// Splits `dests` between workers, by the inode returned by `version`
+    fn partition<T>(&self, dests: Vec<T>, version: impl Fn(&T) -> &FileVersion) -> Vec<Vec<T>> {
+        let mut batches: Vec<Vec<T>> = self.senders.iter().map(|_| Vec::new()).collect();
+        for dest in dests {
+            let version = version(&dest);
+            let mut hasher = DefaultHasher::new();
+            (version.dev, version.ino).hash(&mut hasher);
+            let worker = (hasher.finish() % self.senders.len() as u64) as usize;
+            batches[worker].push(dest);
+        }
+        batches
+    }
+
+    /// Deduplicates `dests` against `src`, like `dedup::dedup`
+    pub fn dedup(&self, src: BlockLocation, dests: Vec<BlockLocation>) {
+        let batches = self.partition(dests, |dest| &dest.version);
+        for (sender, dests) in self.senders.iter().zip(batches) {
+            if !dests.is_empty() {
+                let src = src.clone();
+                sender.send(DedupJob::Ranges { src, dests }).unwrap();
+            }
+        }
+    }
+
+    /// Deduplicates whole `dests` files against `src`, like `dedup::dedup_files`
+    pub fn dedup_files(
+        &self,
+        src: &Path,
+        src_version: FileVersion,
+        dests: Vec<(PathBuf, FileVersion)>,
+    ) {
+        let batches = self.partition(dests, |(_, version)| version);
+        for (sender, dests) in self.senders.iter().zip(batches) {
+            if !dests.is_empty() {
+                sender
+                    .send(DedupJob::Files {
+                        src: src.to_path_buf(),
+                        src_version,
+                        dests,
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Waits for all submitted requests. Returns the results of all workers.
+    pub fn finish(self) -> RunReport {
+        drop(self.senders);
+
+        let mut report = RunReport::default();
+        for handle in self.handles {
+            report.merge(handle.join().unwrap());
+        }
+        report
+    }
+}